@@ -0,0 +1,386 @@
+use crate::types::address::Address;
+use crate::types::errors::{BError, BResult};
+use crate::types::retry::with_retry;
+use crate::types::types::{ChainRpcConfig, PayBySig, RetryConfig, WithdrawItem};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub enum SwapVerdict {
+    Confirmed,
+    /// Not found or not confirmed yet. May change later
+    Pending(String),
+    /// The swap does not support the withdraw item
+    Rejected(String),
+}
+
+#[derive(Deserialize)]
+struct RpcResponse<T> {
+    result: Option<T>,
+    error: Option<Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Receipt {
+    status: Option<String>,
+    block_number: Option<String>,
+    #[serde(default)]
+    logs: Vec<Log>,
+}
+
+#[derive(Deserialize)]
+struct Log {
+    address: String,
+    #[serde(default)]
+    topics: Vec<String>,
+    #[serde(default)]
+    data: String,
+    #[serde(default)]
+    removed: bool,
+}
+
+impl Log {
+    /// The indexed and non indexed 32 byte words, in lowercase hex
+    fn words(&self) -> Vec<String> {
+        let data = self.data.strip_prefix("0x").unwrap_or(&self.data).to_lowercase();
+        self.topics
+            .iter()
+            .skip(1)
+            .map(|t| t.strip_prefix("0x").unwrap_or(t).to_lowercase())
+            .chain(data.as_bytes().chunks(64).map(|w| String::from_utf8_lossy(w).to_string()))
+            .collect()
+    }
+}
+
+fn rpc_err_map(e: reqwest::Error) -> BError {
+    let msg = format!("Error calling the RPC endpoint '{}': {}",
+        e.url().map(|u| u.as_str()).unwrap_or(""), e);
+    match e.is_timeout() || e.is_connect() || e.status().is_some_and(|s| s.is_server_error()) {
+        true => BError::transient(&msg),
+        false => BError::new(&msg),
+    }
+}
+
+/// The token, payee and amount of the withdrawal, as ABI encoded words
+fn withdrawal_words(pbs: &PayBySig) -> BResult<Vec<String>> {
+    let amount: u128 = pbs.amount.parse().map_err(|_| BError::new(&format!(
+        "Cannot parse the amount '{}'", &pbs.amount)))?;
+    Ok(vec![
+        format!("{:0>64}", &Address::parse(&pbs.token)?.to_string()[2..]),
+        format!("{:0>64}", &Address::parse(&pbs.payee)?.to_string()[2..]),
+        format!("{:064x}", amount),
+    ])
+}
+
+fn parse_quantity(q: &str) -> BResult<u64> {
+    let h = q.strip_prefix("0x").unwrap_or(q);
+    u64::from_str_radix(h, 16).map_err(|_| BError::new(&format!("Invalid quantity '{}'", q)))
+}
+
+/**
+Confirms the swap behind a withdraw item on the chain it was sent on, using the
+JSON-RPC endpoint configured for the item's send network. Timeouts, connection
+and 5xx errors are retried.
+ **/
+pub struct EvmVerifier {
+    chains: HashMap<String, ChainRpcConfig>,
+    client: reqwest::Client,
+    retry: RetryConfig,
+}
+
+impl EvmVerifier {
+    pub fn new(chains: &HashMap<String, ChainRpcConfig>, retry: &RetryConfig) -> Self {
+        EvmVerifier {
+            chains: chains.clone(),
+            client: reqwest::Client::new(),
+            retry: retry.clone(),
+        }
+    }
+
+    async fn call<T: DeserializeOwned>(&self, chain: &ChainRpcConfig, method: &str, params: Value)
+        -> BResult<Option<T>> {
+        with_retry(&self.retry, method, |e: &BError| e.transient, || {
+            self.call_once(chain, method, params.clone())
+        }).await
+    }
+
+    async fn call_once<T: DeserializeOwned>(&self, chain: &ChainRpcConfig, method: &str, params: Value)
+        -> BResult<Option<T>> {
+        let body = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        let res: RpcResponse<T> = self.client
+            .post(&chain.url)
+            .timeout(Duration::from_secs(chain.timeout_secs))
+            .json(&body)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(rpc_err_map)?
+            .json()
+            .await
+            .map_err(rpc_err_map)?;
+        if let Some(e) = res.error {
+            return Err(BError::new(&format!("{} failed: {}", method, e)));
+        }
+        Ok(res.result)
+    }
+
+    /**
+    The swap transaction must be on the chain of the item's send network, have
+    succeeded, have enough confirmations, and have a log from the bridge contract
+    that holds the token, payee and amount of the withdrawal. Those are matched as
+    ABI words, whatever their position in the event.
+     **/
+    pub async fn verify(&self, wi: &WithdrawItem) -> BResult<SwapVerdict> {
+        let chain = match self.chains.get(&wi.send_network) {
+            Some(c) => c,
+            None => return Ok(SwapVerdict::Rejected(format!(
+                "No RPC endpoint configured for network {}", &wi.send_network))),
+        };
+        let pbs = &wi.pay_by_sig;
+        let expected = match withdrawal_words(pbs) {
+            Ok(w) => w,
+            Err(e) => return Ok(SwapVerdict::Rejected(e.msg)),
+        };
+        let tx = &pbs.swap_tx_id;
+        let h = tx.strip_prefix("0x").unwrap_or(tx);
+        if h.len() != 64 || hex::decode(h).is_err() {
+            return Ok(SwapVerdict::Rejected(format!("Invalid swap transaction id '{}'", tx)));
+        }
+        let chain_id: String = self.call(chain, "eth_chainId", json!([])).await?
+            .ok_or_else(|| BError::new("eth_chainId returned nothing"))?;
+        if parse_quantity(&chain_id)? != chain.chain_id {
+            return Ok(SwapVerdict::Rejected(format!(
+                "RPC endpoint is for chain {}, expected {}", &chain_id, chain.chain_id)));
+        }
+        let receipt: Receipt = match self.call(
+            chain, "eth_getTransactionReceipt", json!([tx])).await? {
+            Some(r) => r,
+            None => return Ok(SwapVerdict::Pending(format!("Swap transaction {} not found", tx))),
+        };
+        let block = match &receipt.block_number {
+            Some(b) => parse_quantity(b)?,
+            None => return Ok(SwapVerdict::Pending(format!("Swap transaction {} not mined", tx))),
+        };
+        if receipt.status.as_deref() != Some("0x1") {
+            return Ok(SwapVerdict::Rejected(format!("Swap transaction {} failed", tx)));
+        }
+        let swap_logs: Vec<&Log> = receipt.logs.iter().filter(|l| {
+            !l.removed
+                && Address::parse(&l.address).ok() == Some(chain.bridge_contract)
+                && chain.swap_event_topic.as_ref().is_none_or(
+                    |t| l.topics.first().is_some_and(|t0| t0.eq_ignore_ascii_case(t)))
+        }).collect();
+        if swap_logs.is_empty() {
+            return Ok(SwapVerdict::Rejected(format!(
+                "Swap transaction {} has no swap log from the bridge contract {}",
+                tx, &chain.bridge_contract)));
+        }
+        let matching = swap_logs.iter().any(|l| {
+            let words = l.words();
+            expected.iter().all(|w| words.contains(w))
+        });
+        if !matching {
+            return Ok(SwapVerdict::Rejected(format!(
+                "Swap transaction {} does not swap {} of token {} to {}",
+                tx, &pbs.amount, &pbs.token, &pbs.payee)));
+        }
+        let head: String = self.call(chain, "eth_blockNumber", json!([])).await?
+            .ok_or_else(|| BError::new("eth_blockNumber returned nothing"))?;
+        let confirmations = (parse_quantity(&head)? + 1).saturating_sub(block);
+        if confirmations < chain.min_confirmations {
+            return Ok(SwapVerdict::Pending(format!(
+                "Swap transaction {} has {} of {} confirmations",
+                tx, confirmations, chain.min_confirmations)));
+        }
+        Ok(SwapVerdict::Confirmed)
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::{EvmVerifier, SwapVerdict};
+    #[allow(unused_imports)]
+    use crate::types::address::Address;
+    #[allow(unused_imports)]
+    use crate::types::types::{ChainRpcConfig, PayBySig, RetryConfig, WithdrawItem};
+    #[allow(unused_imports)]
+    use serde_json::{json, Value};
+    #[allow(unused_imports)]
+    use std::collections::HashMap;
+    #[allow(unused_imports)]
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    #[allow(unused_imports)]
+    use tokio::net::TcpListener;
+
+    #[allow(dead_code)]
+    const BRIDGE: &str = "0x0000000000000000000000000000000000000009";
+    #[allow(dead_code)]
+    const SWAP_TX: &str = "0x1111111111111111111111111111111111111111111111111111111111111111";
+    #[allow(dead_code)]
+    const TOKEN: &str = "0x0000000000000000000000000000000000000002";
+    #[allow(dead_code)]
+    const PAYEE: &str = "0x0000000000000000000000000000000000000003";
+
+    /// Serves canned JSON-RPC results by method. Returns the url
+    #[allow(dead_code)]
+    async fn mock_rpc(results: HashMap<&'static str, Value>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0u8; 4096];
+                // Read the headers, then the body
+                let body = loop {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    req.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&req).to_string();
+                    if let Some(pos) = text.find("\r\n\r\n") {
+                        let len: usize = text[..pos]
+                            .lines()
+                            .find_map(|l| l.to_lowercase().strip_prefix("content-length:")
+                                .map(|v| v.trim().parse().unwrap()))
+                            .unwrap_or(0);
+                        if req.len() >= pos + 4 + len {
+                            break req[pos + 4..pos + 4 + len].to_vec();
+                        }
+                    }
+                };
+                let call: Value = serde_json::from_slice(&body).unwrap();
+                let method = call["method"].as_str().unwrap();
+                let result = results.get(method).cloned().unwrap_or(Value::Null);
+                let res = json!({ "jsonrpc": "2.0", "id": 1, "result": result }).to_string();
+                let http = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                    Connection: close\r\n\r\n{}", res.len(), res);
+                socket.write_all(http.as_bytes()).await.unwrap();
+            }
+        });
+        url
+    }
+
+    /// A swap log with the payee as an indexed topic, and the token and amount as data
+    #[allow(dead_code)]
+    fn receipt_with(status: &str, log_address: &str, amount: u128) -> Value {
+        let word = |a: &str| format!("{:0>64}", a.strip_prefix("0x").unwrap());
+        json!({
+            "status": status,
+            "blockNumber": "0x64",
+            "logs": [{
+                "address": log_address,
+                "topics": ["0xaa", format!("0x{}", word(PAYEE))],
+                "data": format!("0x{}{:064x}", word(TOKEN), amount),
+                "removed": false,
+            }],
+        })
+    }
+
+    #[allow(dead_code)]
+    fn receipt(status: &str, log_address: &str) -> Value {
+        receipt_with(status, log_address, 1000)
+    }
+
+    /// Swapped on BSC, withdrawn on RINKEBY
+    #[allow(dead_code)]
+    fn withdraw_item() -> WithdrawItem {
+        WithdrawItem {
+            v: 1,
+            version: String::from("1.0"),
+            receive_network: String::from("RINKEBY"),
+            receive_transaction_id: String::from("0x1"),
+            send_network: String::from("BSC"),
+            signatures: 0,
+            pay_by_sig: PayBySig {
+                token: String::from(TOKEN),
+                payee: String::from(PAYEE),
+                amount: String::from("1000"),
+                source_chain_id: 4,
+                swap_tx_id: String::from(SWAP_TX),
+                contract_name: String::new(),
+                contract_version: String::new(),
+                contract_address: String::new(),
+                hash: String::new(),
+                signatures: vec![],
+            },
+        }
+    }
+
+    #[allow(dead_code)]
+    async fn verify(results: HashMap<&'static str, Value>) -> SwapVerdict {
+        let url = mock_rpc(results).await;
+        let mut chains = HashMap::new();
+        chains.insert(String::from("BSC"), ChainRpcConfig {
+            url,
+            chain_id: 56,
+            bridge_contract: Address::parse(BRIDGE).ok().unwrap(),
+            swap_event_topic: Some(String::from("0xAA")),
+            min_confirmations: 10,
+            timeout_secs: 10,
+        });
+        EvmVerifier::new(&chains, &RetryConfig::default()).verify(&withdraw_item()).await.ok()
+            .expect("Verification failed")
+    }
+
+    #[tokio::test]
+    async fn test_verify_swap() {
+        let rpc = |chain_id: &str, receipt: Value, head: &str| {
+            let mut results = HashMap::new();
+            results.insert("eth_chainId", json!(chain_id));
+            results.insert("eth_getTransactionReceipt", receipt);
+            results.insert("eth_blockNumber", json!(head));
+            results
+        };
+        assert!(matches!(verify(rpc("0x38", receipt("0x1", BRIDGE), "0x6d")).await,
+                         SwapVerdict::Confirmed));
+        assert!(matches!(verify(rpc("0x38", receipt("0x1", BRIDGE), "0x6c")).await,
+                         SwapVerdict::Pending(_)), "Not enough confirmations");
+        assert!(matches!(verify(rpc("0x38", Value::Null, "0x6d")).await,
+                         SwapVerdict::Pending(_)), "Swap not found");
+        assert!(matches!(verify(rpc("0x38", receipt("0x0", BRIDGE), "0x6d")).await,
+                         SwapVerdict::Rejected(_)), "Failed swap");
+        let other = "0x0000000000000000000000000000000000000008";
+        assert!(matches!(verify(rpc("0x38", receipt("0x1", other), "0x6d")).await,
+                         SwapVerdict::Rejected(_)), "Log from another contract");
+        assert!(matches!(verify(rpc("0x38", receipt_with("0x1", BRIDGE, 1), "0x6d")).await,
+                         SwapVerdict::Rejected(_)), "Swap of a smaller amount");
+        assert!(matches!(verify(rpc("0x4", receipt("0x1", BRIDGE), "0x6d")).await,
+                         SwapVerdict::Rejected(_)), "Endpoint for the withdrawal chain");
+    }
+
+    #[tokio::test]
+    async fn test_hung_endpoint_times_out() {
+        // Accepts the connections, never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let accepted = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            let mut sockets = vec![];
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                sockets.push(socket);
+            }
+        });
+        let mut chains = HashMap::new();
+        chains.insert(String::from("BSC"), ChainRpcConfig {
+            url,
+            chain_id: 56,
+            bridge_contract: Address::parse(BRIDGE).ok().unwrap(),
+            swap_event_topic: None,
+            min_confirmations: 0,
+            timeout_secs: 1,
+        });
+        let retry = RetryConfig { max_attempts: 2, initial_delay_ms: 10, max_delay_ms: 10, max_jitter_ms: 0 };
+        let res = tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            EvmVerifier::new(&chains, &retry).verify(&withdraw_item())).await;
+        let e = res.expect("Must time out").err().expect("Must fail");
+        assert!(e.transient);
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 2, "Must be retried");
+    }
+}
@@ -1,22 +1,76 @@
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+fn non_zero_secs(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>().map_err(|e| e.to_string())? {
+        0 => Err(String::from("must be at least 1 second")),
+        secs => Ok(secs),
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Keep the node running and process pending withdraw items on a schedule
+    Run {
+        /// Seconds to wait between two processing rounds
+        #[structopt(short, long, default_value = "5", parse(try_from_str = non_zero_secs))]
+        interval: u64,
+
+        /// Maximum random delay in seconds added to every interval
+        #[structopt(short, long, default_value = "0")]
+        jitter: u64,
+
+        /// Re-check withdraw items as soon as they change in the db. The interval is
+        /// then used for the fallback full sweep. Requires a Mongo replica set
+        #[structopt(short, long)]
+        watch: bool,
+    },
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "bridge-v1-node")]
 pub struct Opt {
     #[structopt(short = "c", long, parse(from_os_str))]
     pub config: PathBuf,
 
-    #[structopt(short = "n", long)]
-    pub network: String,
+    /// Networks to process. Can be repeated or comma separated. Defaults to
+    /// the `networks` in the config file
+    #[structopt(short = "n", long, use_delimiter = true, number_of_values = 1)]
+    pub network: Vec<String>,
 
+    /// Only process the withdraw item with this receive transaction id and print
+    /// a detailed verdict
     #[structopt(short, long)]
     pub transaction_id: Option<String>,
 
     #[structopt(long)]
     pub insecure: bool,
+
+    /// Validate and produce our signatures, but do not write them to the db
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    /// Seconds to wait for the in-flight items to finish after SIGTERM or SIGINT
+    #[structopt(long, default_value = "30")]
+    pub shutdown_timeout: u64,
+
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
 }
 
 pub fn cli() -> Opt {
     Opt::from_args()
 }
+
+mod test {
+    #[allow(unused_imports)]
+    use super::Opt;
+    #[allow(unused_imports)]
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_zero_interval_is_rejected() {
+        assert!(Opt::from_iter_safe(&["node", "-c", "c.json", "run", "--interval", "1"]).is_ok());
+        assert!(Opt::from_iter_safe(&["node", "-c", "c.json", "run", "--interval", "0"]).is_err());
+    }
+}
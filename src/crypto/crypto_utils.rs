@@ -3,6 +3,13 @@ use secp256k1::{key::SecretKey, Message, PublicKey, Secp256k1};
 use std::fmt;
 use tiny_keccak::{Hasher, Keccak};
 use rand::{RngCore, thread_rng};
+use crate::types::errors::{BError, BResult};
+
+/// Half the order of secp256k1. A signature with a higher s is malleable (EIP-2)
+const SECP256K1_HALF_N: [u8; 32] = [
+    0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0x5d, 0x57, 0x6e, 0x73, 0x57, 0xa4, 0x50, 0x1d, 0xdf, 0xe9, 0x2f, 0x46, 0x68, 0x1b, 0x20, 0xa0,
+];
 
 pub struct EcdsaSig {
     v: u64,
@@ -49,6 +56,11 @@ impl EcdsaSig {
         Vec::from(rv)
     }
 
+    /// Low s, and v as either 0/1 or 27/28
+    pub fn is_canonical(&self) -> bool {
+        self.s.as_slice() <= &SECP256K1_HALF_N[..] && matches!(self.v, 0 | 1 | 27 | 28)
+    }
+
     #[allow(dead_code)]
     pub fn to_hex(&self) -> String {
         b2h(&self.to_u8())
@@ -71,6 +83,12 @@ pub fn h2b(h: &String) -> Vec<u8> {
     }
 }
 
+/// Decodes hex, with or without a 0x prefix
+pub fn decode_hex(h: &str) -> BResult<Vec<u8>> {
+    let trimmed = h.strip_prefix("0x").or_else(|| h.strip_prefix("0X")).unwrap_or(h);
+    hex::decode(trimmed).map_err(|_| BError::new(&format!("Invalid hex '{}'", h)))
+}
+
 pub fn keccak256_hash(bytes: &[u8]) -> Vec<u8> {
     let mut hasher = Keccak::v256();
     hasher.update(bytes);
@@ -126,10 +144,10 @@ fn ecdsa_sign(hash: &[u8], private_key: &[u8]) -> EcdsaSig {
 
 fn ecdsa_recover(hash: &[u8], sig: &EcdsaSig) -> Result<Vec<u8>, secp256k1::Error> {
     let s = Secp256k1::new();
-    let msg = Message::from_slice(hash).unwrap();
+    let msg = Message::from_slice(hash)?;
     let mut sig_compact: Vec<u8> = sig.r.clone();
     sig_compact.extend(&sig.s);
-    let sig_v = RecoveryId::from_i32(sig.v.clone() as i32).unwrap();
+    let sig_v = RecoveryId::from_i32(sig.v as i32)?;
     let rec_sig = RecoverableSignature::from_compact(&sig_compact, sig_v);
     match rec_sig {
         Ok(r) => {
@@ -155,8 +173,15 @@ impl CryptoUtils {
         sig.to_u8()
     }
 
-    pub fn recover(&self, hash: &[u8], sig: &[u8]) -> Vec<u8> {
-        let sig_o = EcdsaSig::from(sig).unwrap();
-        ecdsa_recover(hash, &sig_o).unwrap()
+    /// Only recovers canonical signatures, so that an approval has a single encoding
+    pub fn recover_strict(&self, hash: &[u8], sig: &[u8]) -> Result<Vec<u8>, secp256k1::Error> {
+        let mut sig_o = EcdsaSig::from(sig)?;
+        if !sig_o.is_canonical() {
+            return Err(secp256k1::Error::IncorrectSignature);
+        }
+        if sig_o.v >= 27 {
+            sig_o.v -= 27;
+        }
+        ecdsa_recover(hash, &sig_o)
     }
 }
@@ -28,7 +28,7 @@ impl EncryptedData {
     }
 }
 
-pub trait DirectCryptor {
+pub trait DirectCryptor: Send + Sync {
     fn decrypt_to_hex(&self, d: &String, key: &String) -> BResult<String>;
     fn encrypt_hex(&self, raw_data_hex: &String, key: &String) -> BResult<String>;
 }
@@ -0,0 +1,160 @@
+use crate::crypto::crypto_utils::{b2h, decode_hex, keccak256_hash};
+use crate::types::errors::{BError, BResult};
+use crate::types::types::PayBySig;
+use ethereum_types::U256;
+
+const DOMAIN_TYPE: &str =
+    "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)";
+const WITHDRAW_SIGNED_TYPE: &str =
+    "WithdrawSigned(address token,address payee,uint256 amount,bytes32 salt)";
+
+pub struct Eip712Domain<'a> {
+    pub name: &'a str,
+    pub version: &'a str,
+    pub chain_id: u64,
+    pub verifying_contract: &'a str,
+}
+
+/// abi encoding of an address: left padded to 32 bytes
+pub fn encode_address(address: &str) -> BResult<Vec<u8>> {
+    let b = decode_hex(address)?;
+    if b.len() != 20 {
+        return Err(BError::new(&format!("Invalid address '{}'", address)));
+    }
+    let mut rv = vec![0u8; 12];
+    rv.extend(b);
+    Ok(rv)
+}
+
+pub fn encode_u256(v: U256) -> Vec<u8> {
+    let mut rv = vec![0u8; 32];
+    v.to_big_endian(&mut rv);
+    rv
+}
+
+pub fn domain_separator(d: &Eip712Domain) -> BResult<Vec<u8>> {
+    let mut enc = keccak256_hash(DOMAIN_TYPE.as_bytes());
+    enc.extend(keccak256_hash(d.name.as_bytes()));
+    enc.extend(keccak256_hash(d.version.as_bytes()));
+    enc.extend(encode_u256(U256::from(d.chain_id)));
+    enc.extend(encode_address(d.verifying_contract)?);
+    Ok(keccak256_hash(&enc))
+}
+
+/// keccak256("\x19\x01" ‖ domainSeparator ‖ hashStruct(message))
+pub fn typed_data_hash(domain_separator: &[u8], struct_hash: &[u8]) -> Vec<u8> {
+    let mut enc: Vec<u8> = vec![0x19, 0x01];
+    enc.extend(domain_separator);
+    enc.extend(struct_hash);
+    keccak256_hash(&enc)
+}
+
+/**
+Salt used by the bridge for a swap: keccak256 of the lower cased swap tx id. Like
+web3's keccak256, a 0x prefixed hex id is hashed as bytes, anything else as utf8.
+ **/
+pub fn swap_salt(swap_tx_id: &str) -> Vec<u8> {
+    let id = swap_tx_id.to_lowercase();
+    match id.starts_with("0x") {
+        true => match decode_hex(&id) {
+            Ok(b) => keccak256_hash(&b),
+            Err(_) => keccak256_hash(id.as_bytes()),
+        },
+        false => keccak256_hash(id.as_bytes()),
+    }
+}
+
+/**
+Rebuilds the hash that validators and super signers sign for a withdrawal, from
+the pay by signature fields. The domain is the bridge contract the withdrawal is
+executed on.
+ **/
+pub fn withdraw_signed_hash(pbs: &PayBySig) -> BResult<String> {
+    let domain = Eip712Domain {
+        name: &pbs.contract_name,
+        version: &pbs.contract_version,
+        chain_id: pbs.source_chain_id as u64,
+        verifying_contract: &pbs.contract_address,
+    };
+    let amount = U256::from_dec_str(&pbs.amount)
+        .map_err(|_| BError::new(&format!("Invalid amount '{}'", &pbs.amount)))?;
+    let mut enc = keccak256_hash(WITHDRAW_SIGNED_TYPE.as_bytes());
+    enc.extend(encode_address(&pbs.token)?);
+    enc.extend(encode_address(&pbs.payee)?);
+    enc.extend(encode_u256(amount));
+    enc.extend(swap_salt(&pbs.swap_tx_id));
+    let struct_hash = keccak256_hash(&enc);
+    let hash = typed_data_hash(&domain_separator(&domain)?, &struct_hash);
+    Ok(format!("0x{}", b2h(&hash)))
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::{domain_separator, encode_address, typed_data_hash, withdraw_signed_hash, Eip712Domain};
+    #[allow(unused_imports)]
+    use crate::crypto::crypto_utils::{b2h, keccak256_hash};
+    #[allow(unused_imports)]
+    use crate::types::types::PayBySig;
+
+    #[allow(dead_code)]
+    fn person_hash(name: &str, wallet: &str) -> Vec<u8> {
+        let mut enc = keccak256_hash("Person(string name,address wallet)".as_bytes());
+        enc.extend(keccak256_hash(name.as_bytes()));
+        enc.extend(encode_address(wallet).ok().unwrap());
+        keccak256_hash(&enc)
+    }
+
+    /// Example from the EIP-712 specification
+    #[test]
+    fn test_eip712_mail_example() {
+        let domain = Eip712Domain {
+            name: "Ether Mail",
+            version: "1",
+            chain_id: 1,
+            verifying_contract: "0xCcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC",
+        };
+        let ds = domain_separator(&domain).ok().expect("Bad domain");
+        assert_eq!(b2h(&ds), "f2cee375fa42b42143804025fc449deafd50cc031ca257e0b194a650a912090f");
+
+        let mut enc = keccak256_hash(
+            "Mail(Person from,Person to,string contents)Person(string name,address wallet)"
+                .as_bytes());
+        enc.extend(person_hash("Cow", "0xCD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826"));
+        enc.extend(person_hash("Bob", "0xbBbBBBBbbBBBbbbBbbBbbbbBBbBbbbbBbBbbBBbB"));
+        enc.extend(keccak256_hash("Hello, Bob!".as_bytes()));
+        let digest = typed_data_hash(&ds, &keccak256_hash(&enc));
+        assert_eq!(b2h(&digest), "be609aee343fb3c4b28e1df9e632fca64fcfaede20f02e86244efddf30957bd2");
+    }
+
+    #[allow(dead_code)]
+    fn pay_by_sig(swap_tx_id: &str) -> PayBySig {
+        PayBySig {
+            token: String::from("0xa719b8ab7ea7af0ddb4358719a34631bb79d15dc"),
+            payee: String::from("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"),
+            amount: String::from("1234500000000000000000"),
+            source_chain_id: 56,
+            swap_tx_id: String::from(swap_tx_id),
+            contract_name: String::from("FERRUM_TOKEN_BRIDGE_POOL"),
+            contract_version: String::from("000.003"),
+            contract_address: String::from("0x8e21e0bc4ccb9ae6b7f3ed9f3ee6f6a9dfe0a4c1"),
+            hash: String::new(),
+            signatures: vec![],
+        }
+    }
+
+    /**
+    Golden digests computed outside of this crate, with a standalone keccak-256 and
+    the abi encoding of `WithdrawSigned(address token,address payee,uint256 amount,
+    bytes32 salt)` in the domain of the contract on chain 56. They pin the type
+    string, the domain chain id, and both salt rules.
+     **/
+    #[test]
+    fn test_withdraw_signed_hash_golden() {
+        let hex_tx = "0x9F1e5b0c4d0dD7fB1a3f6aA7c2B0E1a4C3d2E1f0a9B8c7D6e5F4a3B2c1D0e9F8";
+        assert_eq!(withdraw_signed_hash(&pay_by_sig(hex_tx)).ok().unwrap(),
+                   "0x30216dddec401092ab5ae2f0765c43b4f975af6d733fd206d0fed07c1fd40811");
+        // Not hex: the lower cased id is hashed as utf8
+        assert_eq!(withdraw_signed_hash(&pay_by_sig("Swap-Tx-7")).ok().unwrap(),
+                   "0x85682a994a90b8e94ccb4dbe2f98354693c6e21e60d4d311220e12b127ade57f");
+    }
+}
@@ -2,7 +2,7 @@ use crate::BResult;
 use crate::crypto::crypto_utils::{rand_hex32};
 use crate::crypto::cryptor::{DirectCryptor, EncryptedData};
 
-pub trait EnvelopeCryptor {
+pub trait EnvelopeCryptor: Send + Sync {
     fn decrypt_to_hex(&self, d: &EncryptedData, kek: &String) -> BResult<String>;
     fn encrypt_hex(&self, raw_data_hex: &String, kek: &String) -> BResult<EncryptedData>;
 }
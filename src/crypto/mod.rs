@@ -1,4 +1,5 @@
 pub mod crypto_utils;
+pub mod eip712;
 pub mod hmac;
 pub mod cryptor;
 pub mod local_cryptor;
@@ -1,23 +1,47 @@
-use crate::types::types::{DbConfig, PayBySig, SignedSwap, WithdrawItem, WithdrawItemSignature};
+use crate::types::address::Address;
+use crate::types::retry::with_retry;
+use crate::types::types::{
+    DbConfig, PayBySig, RetryConfig, SignedSwap, ValidationReport, WithdrawItem,
+    WithdrawItemSignature,
+};
+use crate::types::utils::now;
 use async_trait::async_trait;
+use futures::stream::BoxStream;
 use mongodb::bson::doc;
-use mongodb::bson::document::ValueAccessResult;
+use mongodb::bson::document::{ValueAccessError, ValueAccessResult};
 use mongodb::bson::Document;
-use mongodb::error::Result;
+use mongodb::change_stream::event::ChangeStreamEvent;
+use mongodb::error::{Error, ErrorKind, Result, WriteFailure};
+use mongodb::options::{
+    ChangeStreamOptions, FindOneAndUpdateOptions, FullDocumentType, ReplaceOptions,
+    ReturnDocument,
+};
 use mongodb::{Collection, Cursor};
 use tokio_stream::StreamExt;
 
 pub const MONGO_SCHEMA_VERSION: &str = "1.0";
 
+/// Stream of (receive network, receive transaction id) of the withdraw items that
+/// may need to be re-checked.
+pub type WithdrawItemChanges = BoxStream<'static, Result<(String, String)>>;
+
+/// Pending withdraw items. The ones that cannot be parsed are kept apart, as
+/// (receive transaction id, error), so they can be reported
+#[derive(Default)]
+pub struct PendingWithdrawItems {
+    pub items: Vec<WithdrawItem>,
+    pub malformed: Vec<(String, String)>,
+}
+
 #[async_trait]
-pub trait Database {
+pub trait Database: Send + Sync {
     async fn add_signature_to_withdraw_item(
         &self,
         network: &String,
         transaction_id: &String,
         _v: i32,
         wis: &WithdrawItemSignature,
-    ) -> Result<Document>;
+    ) -> Result<Option<Document>>;
 
     async fn signed_swaps(
         &self,
@@ -25,16 +49,90 @@ pub trait Database {
         transaction_id: &String,
     ) -> Result<Vec<SignedSwap>>;
 
-    async fn pending_withdraw_items(&self, network: &String) -> Result<Vec<WithdrawItem>>;
+    /// Items with fewer than `super_threshold` super signatures
+    async fn pending_withdraw_items(&self, network: &String, super_threshold: u32)
+        -> Result<PendingWithdrawItems>;
+
+    async fn withdraw_item(
+        &self,
+        network: &str,
+        transaction_id: &str,
+    ) -> Result<Option<WithdrawItem>>;
+
+    async fn watch_withdraw_items(&self, networks: &[String]) -> Result<WithdrawItemChanges>;
+
+    /// Take or renew the lease. Returns false if it is held by another owner
+    async fn acquire_lease(&self, key: &str, owner: &str, ttl_ms: i64) -> Result<bool>;
+
+    async fn release_lease(&self, key: &str, owner: &str) -> Result<()>;
+
+    /// Replaces the latest validation report of the withdraw item
+    async fn save_validation_report(&self, report: &ValidationReport) -> Result<()>;
+
+    /// (kind, value) of every blocklist entry
+    async fn blocklist_entries(&self) -> Result<Vec<(String, String)>>;
+
+    /// (receive transaction id, amount) of the items for the token that got a super
+    /// signature after `since`
+    async fn signed_amounts(&self, network: &str, token: &Address, since: i64)
+        -> Result<Vec<(String, String)>>;
+
+    /// Whether the volume cap was reached. It stays reached until its document is removed
+    async fn is_volume_cap_reached(&self, key: &str) -> Result<bool>;
+
+    async fn set_volume_cap_reached(&self, key: &str, reason: &str) -> Result<()>;
+}
+
+// Server error codes for a primary step-down, or a node shutting down or unreachable.
+const TRANSIENT_ERROR_CODES: [i32; 11] = [6, 7, 89, 91, 189, 262, 9001, 10107, 11600, 11602, 13435];
+
+/**
+Network errors and primary step-downs are transient. Anything else (bad query,
+auth, duplicate key, ...) will not go away by retrying.
+ **/
+pub fn is_transient_error(e: &Error) -> bool {
+    if e.contains_label("RetryableWriteError") || e.contains_label("TransientTransactionError") {
+        return true;
+    }
+    match e.kind.as_ref() {
+        ErrorKind::Io(_) => true,
+        ErrorKind::ConnectionPoolCleared { .. } => true,
+        ErrorKind::ServerSelection { .. } => true,
+        ErrorKind::Command(c) => TRANSIENT_ERROR_CODES.contains(&c.code),
+        _ => false,
+    }
+}
+
+/// A document that does not have the expected fields. Not transient
+fn malformed_document_error(d: &Document, e: ValueAccessError) -> Error {
+    <mongodb::bson::de::Error as serde::de::Error>::custom(format!(
+        "Malformed withdraw item {} - {}", d.get_str("receiveTransactionId").unwrap_or(""), e))
+        .into()
+}
+
+const DUPLICATE_KEY_ERROR_CODE: i32 = 11000;
+
+fn is_duplicate_key_error(e: &Error) -> bool {
+    match e.kind.as_ref() {
+        ErrorKind::Command(c) => c.code == DUPLICATE_KEY_ERROR_CODE,
+        ErrorKind::Write(WriteFailure::WriteError(w)) => w.code == DUPLICATE_KEY_ERROR_CODE,
+        _ => false,
+    }
 }
 
+#[derive(Clone)]
 pub struct DatabaseClient {
     withdraw_items: Box<Collection<Document>>,
     validator_signatures: Box<Collection<Document>>,
+    leases: Box<Collection<Document>>,
+    validation_reports: Box<Collection<Document>>,
+    blocklist: Box<Collection<Document>>,
+    volume_caps: Box<Collection<Document>>,
+    retry: RetryConfig,
 }
 
 impl DatabaseClient {
-    pub async fn new(conf: &DbConfig) -> Result<Self> {
+    pub async fn new(conf: &DbConfig, retry: &RetryConfig) -> Result<Self> {
         let client = mongodb::Client::with_uri_str(&conf.connection_string).await?;
         // println!("Connected using {}", &conf.connection_string);
 
@@ -48,36 +146,65 @@ impl DatabaseClient {
                 .database(&conf.database)
                 .collection("withdrawitemhashverifications"),
         );
+        let leases = Box::new(
+            client
+                .database(&conf.database)
+                .collection("withdrawitemleases"),
+        );
+        let validation_reports = Box::new(
+            client
+                .database(&conf.database)
+                .collection("withdrawitemvalidations"),
+        );
+        let blocklist = Box::new(
+            client
+                .database(&conf.database)
+                .collection("withdrawblocklist"),
+        );
+        let volume_caps = Box::new(
+            client
+                .database(&conf.database)
+                .collection("withdrawvolumecaps"),
+        );
         Ok(DatabaseClient {
             withdraw_items,
             validator_signatures,
+            leases,
+            validation_reports,
+            blocklist,
+            volume_caps,
+            retry: retry.clone(),
         })
     }
 
-    fn doc_to_withdraw_item(&self, d: &Document) -> ValueAccessResult<WithdrawItem> {
+    fn doc_to_withdraw_item(d: &Document) -> ValueAccessResult<WithdrawItem> {
         let dpbs = d.get_document("payBySig")?;
-        let sigs = dpbs.get_array("signatures");
-        // println!("SIGS {}", &sigs.map_or_else(|e| 0 as usize, |s| s.len()));
-        let signatures: Vec<WithdrawItemSignature> = sigs
-            .unwrap()
-            .into_iter()
+        let signatures: Vec<WithdrawItemSignature> = dpbs
+            .get_array("signatures")?
+            .iter()
             .map(|s| {
-                let sig_d = s.as_document().unwrap();
-                WithdrawItemSignature {
-                    signature: String::from(sig_d.get_str("signature").unwrap()),
-                    creator: String::from(sig_d.get_str("creator").unwrap()),
-                    creation_time: sig_d.get_i64("creationTime").unwrap(),
-                }
+                let sig_d = s.as_document().ok_or(ValueAccessError::UnexpectedType)?;
+                Ok(WithdrawItemSignature {
+                    signature: String::from(sig_d.get_str("signature")?),
+                    creator: String::from(sig_d.get_str("creator")?),
+                    creation_time: sig_d.get_i64("creationTime")?,
+                })
             })
-            .collect();
+            .collect::<ValueAccessResult<_>>()?;
         let swap_tx_id = String::from(dpbs.get_str("swapTxId")?);
         let hash = String::from(dpbs.get_str("hash")?);
         let contract_name = String::from(dpbs.get_str("contractName")?);
         let contract_version = String::from(dpbs.get_str("contractVersion")?);
         let contract_address = String::from(dpbs.get_str("contractAddress")?);
         let source_chain_id = dpbs.get_i32("sourceChainId")?;
+        let token = String::from(dpbs.get_str("token")?);
+        let payee = String::from(dpbs.get_str("payee")?);
+        let amount = String::from(dpbs.get_str("amount")?);
 
         let pay_by_sig = PayBySig {
+            token,
+            payee,
+            amount,
             swap_tx_id,
             hash,
             contract_name,
@@ -97,6 +224,21 @@ impl DatabaseClient {
         })
     }
 
+    fn change_to_item(
+        e: Result<ChangeStreamEvent<Document>>,
+        network_field: &str,
+        tx_field: &str,
+    ) -> Option<Result<(String, String)>> {
+        let e = match e {
+            Ok(e) => e,
+            Err(e) => return Some(Err(e)),
+        };
+        let d = e.full_document?;
+        let network = d.get_str(network_field).ok()?;
+        let transaction_id = d.get_str(tx_field).ok()?;
+        Some(Ok((String::from(network), String::from(transaction_id))))
+    }
+
     fn doc_to_signed_swap(&self, d: &Document) -> ValueAccessResult<SignedSwap> {
         let creation_time = d.get_f64("signatureCreationTime")?;
         let signature = String::from(d.get_str("signature")?);
@@ -119,35 +261,41 @@ impl Database for DatabaseClient {
         transaction_id: &String,
         _v: i32,
         wis: &WithdrawItemSignature,
-    ) -> Result<Document> {
-        let new_sig = doc! {
-            "creationTime": wis.creation_time,
-            "creator": wis.creator.clone(),
-            "signature": wis.signature.clone(),
-        };
-        let res: Option<Document> = self
-            .withdraw_items
-            .find_one_and_update(
-                doc! {
-                    "$and": [
-                        { "receiveNetwork": network.clone(), },
-                        { "receiveTransactionId": transaction_id.clone() },
-                        { "v": _v },
-                    ]
-                },
-                doc! {
-                    "$set": {
-                        "signatures": 1,
-                        "v": _v + 1, // v is the optimistic locking version
+    ) -> Result<Option<Document>> {
+        with_retry(&self.retry, "add_signature_to_withdraw_item", is_transient_error, || async {
+            let new_sig = doc! {
+                "creationTime": wis.creation_time,
+                "creator": wis.creator.clone(),
+                "signature": wis.signature.clone(),
+            };
+            let res: Option<Document> = self
+                .withdraw_items
+                .find_one_and_update(
+                    doc! {
+                        "$and": [
+                            { "receiveNetwork": network.clone(), },
+                            { "receiveTransactionId": transaction_id.clone() },
+                            { "v": _v },
+                            // Never add the same signer twice
+                            { "payBySig.signatures.creator": { "$ne": wis.creator.clone() } },
+                        ]
                     },
-                    "$push": {
-                        "payBySig.signatures": new_sig,
-                    }
-                },
-                None,
-            )
-            .await?;
-        Ok(res.unwrap())
+                    doc! {
+                        "$set": {
+                            "v": _v + 1, // v is the optimistic locking version
+                        },
+                        "$inc": {
+                            "signatures": 1,
+                        },
+                        "$push": {
+                            "payBySig.signatures": new_sig,
+                        }
+                    },
+                    None,
+                )
+                .await?;
+            Ok(res)
+        }).await
     }
 
     async fn signed_swaps(
@@ -155,46 +303,310 @@ impl Database for DatabaseClient {
         network: &String,
         transaction_id: &String,
     ) -> Result<Vec<SignedSwap>> {
-        let mut cursor: Cursor<_> = self
-            .validator_signatures
-            .find(
-                doc! {
-                    "$and": [
-                        { "network": network, },
-                        { "transactionId": transaction_id },
-                    ]
-                },
-                None,
-            )
-            .await?;
+        with_retry(&self.retry, "signed_swaps", is_transient_error, || async {
+            let mut cursor: Cursor<_> = self
+                .validator_signatures
+                .find(
+                    doc! {
+                        "$and": [
+                            { "network": network, },
+                            { "transactionId": transaction_id },
+                        ]
+                    },
+                    None,
+                )
+                .await?;
 
-        let mut result: Vec<SignedSwap> = Vec::new();
-        while let Some(doc) = cursor.next().await {
-            result.push(self.doc_to_signed_swap(&doc?).unwrap());
-        }
-        Ok(result)
+            let mut result: Vec<SignedSwap> = Vec::new();
+            while let Some(doc) = cursor.next().await {
+                match self.doc_to_signed_swap(&doc?) {
+                    Ok(ss) => result.push(ss),
+                    Err(e) => println!(
+                        "Ignoring a malformed validator signature for {}:{} - {}",
+                        network, transaction_id, e),
+                }
+            }
+            Ok(result)
+        }).await
+    }
+
+    async fn pending_withdraw_items(&self, network: &String, super_threshold: u32)
+        -> Result<PendingWithdrawItems> {
+        with_retry(&self.retry, "pending_withdraw_items", is_transient_error, || async {
+            let mut cursor: Cursor<_> = self
+                .withdraw_items
+                .find(
+                    doc! {
+                        "$and": [
+                            { "version": MONGO_SCHEMA_VERSION, },
+                            { "receiveNetwork": network, },
+                            { "signatures": { "$lt": super_threshold } },
+                        ]
+                    },
+                    None,
+                )
+                .await?;
+
+            let mut result = PendingWithdrawItems::default();
+            while let Some(doc) = cursor.next().await {
+                let doc = doc?;
+                match Self::doc_to_withdraw_item(&doc) {
+                    Ok(wi) => result.items.push(wi),
+                    Err(e) => result.malformed.push((
+                        String::from(doc.get_str("receiveTransactionId").unwrap_or("")),
+                        format!("Malformed withdraw item - {}", e))),
+                }
+            }
+            Ok(result)
+        }).await
     }
 
-    async fn pending_withdraw_items(&self, network: &String) -> Result<Vec<WithdrawItem>> {
-        let mut cursor: Cursor<_> = self
+    async fn withdraw_item(
+        &self,
+        network: &str,
+        transaction_id: &str,
+    ) -> Result<Option<WithdrawItem>> {
+        with_retry(&self.retry, "withdraw_item", is_transient_error, || async {
+            let res: Option<Document> = self
+                .withdraw_items
+                .find_one(
+                    doc! {
+                        "$and": [
+                            { "version": MONGO_SCHEMA_VERSION, },
+                            { "receiveNetwork": network, },
+                            { "receiveTransactionId": transaction_id },
+                        ]
+                    },
+                    None,
+                )
+                .await?;
+            res.map(|d| Self::doc_to_withdraw_item(&d).map_err(|e| malformed_document_error(&d, e)))
+                .transpose()
+        }).await
+    }
+
+    /**
+    Subscribe to the changes of withdraw items, and of validator signatures for
+    the networks. Change streams require a replica set.
+     **/
+    async fn watch_withdraw_items(&self, networks: &[String]) -> Result<WithdrawItemChanges> {
+        let options = || ChangeStreamOptions::builder()
+            .full_document(Some(FullDocumentType::UpdateLookup))
+            .build();
+        let withdraw_item_changes = self
             .withdraw_items
-            .find(
-                doc! {
-                    "$and": [
-                        { "version": MONGO_SCHEMA_VERSION, },
-                        { "receiveNetwork": network, },
-                        { "signatures": 0 },
-                    ]
-                },
-                None,
+            .watch(
+                vec![doc! {
+                    "$match": {
+                        "operationType": { "$in": ["insert", "update", "replace"] },
+                        "fullDocument.version": MONGO_SCHEMA_VERSION,
+                        // Items with some super signatures may still need ours.
+                        // The processor decides
+                        "fullDocument.receiveNetwork": { "$in": networks },
+                    }
+                }],
+                options(),
+            )
+            .await?
+            .filter_map(|e| DatabaseClient::change_to_item(
+                e, "receiveNetwork", "receiveTransactionId"));
+        let signature_changes = self
+            .validator_signatures
+            .watch(
+                vec![doc! {
+                    "$match": {
+                        "operationType": { "$in": ["insert", "update", "replace"] },
+                        "fullDocument.network": { "$in": networks },
+                    }
+                }],
+                options(),
             )
-            .await?;
+            .await?
+            .filter_map(|e| DatabaseClient::change_to_item(
+                e, "network", "transactionId"));
+        Ok(Box::pin(withdraw_item_changes.merge(signature_changes)))
+    }
+
+    /**
+    The lease is a document keyed by `key`. It can be taken if it does not exist,
+    is expired, or is already ours. If another owner holds it, the filter does not
+    match and the upsert fails with a duplicate key error.
+    Expiry uses the local clock, so the ttl must be much larger than the clock skew
+    between the nodes.
+     **/
+    async fn acquire_lease(&self, key: &str, owner: &str, ttl_ms: i64) -> Result<bool> {
+        with_retry(&self.retry, "acquire_lease", is_transient_error, || async {
+            let now = now();
+            let res = self
+                .leases
+                .find_one_and_update(
+                    doc! {
+                        "_id": key,
+                        "$or": [
+                            { "owner": owner },
+                            { "expiresAt": { "$lt": now } },
+                        ]
+                    },
+                    doc! {
+                        "$set": {
+                            "owner": owner,
+                            "expiresAt": now + ttl_ms,
+                        }
+                    },
+                    FindOneAndUpdateOptions::builder()
+                        .upsert(true)
+                        .return_document(ReturnDocument::After)
+                        .build(),
+                )
+                .await;
+            match res {
+                Ok(_) => Ok(true),
+                Err(e) if is_duplicate_key_error(&e) => Ok(false),
+                Err(e) => Err(e),
+            }
+        }).await
+    }
 
-        let mut result: Vec<WithdrawItem> = Vec::new();
-        while let Some(doc) = cursor.next().await {
-            println!("Pushing a WI");
-            result.push(self.doc_to_withdraw_item(&doc?).unwrap());
+    async fn release_lease(&self, key: &str, owner: &str) -> Result<()> {
+        with_retry(&self.retry, "release_lease", is_transient_error, || async {
+            self.leases
+                .delete_one(doc! { "_id": key, "owner": owner }, None)
+                .await?;
+            Ok(())
+        }).await
+    }
+
+    async fn save_validation_report(&self, report: &ValidationReport) -> Result<()> {
+        let id = format!("{}:{}", &report.network, &report.transaction_id);
+        let rejected: Vec<Document> = report
+            .rejected
+            .iter()
+            .map(|(signer, reason)| doc! { "signer": signer, "reason": reason })
+            .collect();
+        let d = doc! {
+            "_id": &id,
+            "receiveNetwork": &report.network,
+            "receiveTransactionId": &report.transaction_id,
+            "node": &report.node,
+            "outcome": &report.outcome,
+            "reason": &report.reason,
+            "signatures": report.signatures as i64,
+            "validWeight": report.valid_weight as i64,
+            "requiredWeight": report.required_weight as i64,
+            "rejected": rejected,
+            "creationTime": report.creation_time,
+        };
+        with_retry(&self.retry, "save_validation_report", is_transient_error, || async {
+            self.validation_reports
+                .replace_one(
+                    doc! { "_id": &id },
+                    d.clone(),
+                    ReplaceOptions::builder().upsert(true).build(),
+                )
+                .await?;
+            Ok(())
+        }).await
+    }
+
+    async fn blocklist_entries(&self) -> Result<Vec<(String, String)>> {
+        with_retry(&self.retry, "blocklist_entries", is_transient_error, || async {
+            let mut cursor: Cursor<Document> = self.blocklist.find(doc! {}, None).await?;
+            let mut entries = Vec::new();
+            while let Some(d) = cursor.next().await {
+                let d = d?;
+                match (d.get_str("kind"), d.get_str("value")) {
+                    (Ok(kind), Ok(value)) => entries.push((kind.to_string(), value.to_string())),
+                    _ => println!("Ignoring a malformed blocklist entry {}", d),
+                }
+            }
+            Ok(entries)
+        }).await
+    }
+
+    async fn signed_amounts(&self, network: &str, token: &Address, since: i64)
+        -> Result<Vec<(String, String)>> {
+        with_retry(&self.retry, "signed_amounts", is_transient_error, || async {
+            let mut cursor: Cursor<Document> = self
+                .withdraw_items
+                .find(
+                    doc! {
+                        "$and": [
+                            { "version": MONGO_SCHEMA_VERSION, },
+                            { "receiveNetwork": network, },
+                            // Stored tokens may be checksummed
+                            { "payBySig.token": {
+                                "$regex": format!("^{}$", token), "$options": "i" } },
+                            { "payBySig.signatures.creationTime": { "$gt": since } },
+                        ]
+                    },
+                    None,
+                )
+                .await?;
+            let mut amounts = Vec::new();
+            while let Some(d) = cursor.next().await {
+                let d = d?;
+                let amount = d.get_document("payBySig").and_then(|p| p.get_str("amount"));
+                match (d.get_str("receiveTransactionId"), amount) {
+                    (Ok(tx), Ok(amount)) => amounts.push((tx.to_string(), amount.to_string())),
+                    // Counting it as 0 could let the cap be exceeded
+                    _ => return Err(malformed_document_error(&d, ValueAccessError::NotPresent)),
+                }
+            }
+            Ok(amounts)
+        }).await
+    }
+
+    async fn is_volume_cap_reached(&self, key: &str) -> Result<bool> {
+        with_retry(&self.retry, "is_volume_cap_reached", is_transient_error, || async {
+            let res = self.volume_caps.find_one(doc! { "_id": key }, None).await?;
+            Ok(res.is_some())
+        }).await
+    }
+
+    async fn set_volume_cap_reached(&self, key: &str, reason: &str) -> Result<()> {
+        with_retry(&self.retry, "set_volume_cap_reached", is_transient_error, || async {
+            self.volume_caps
+                .replace_one(
+                    doc! { "_id": key },
+                    doc! { "_id": key, "reason": reason, "creationTime": now() },
+                    ReplaceOptions::builder().upsert(true).build(),
+                )
+                .await?;
+            Ok(())
+        }).await
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::DatabaseClient;
+    #[allow(unused_imports)]
+    use mongodb::bson::{doc, Document};
+
+    #[allow(dead_code)]
+    fn item_doc(signatures: Vec<Document>) -> Document {
+        doc! {
+            "v": 1, "version": "1.0", "receiveNetwork": "RINKEBY", "signatures": 1,
+            "receiveTransactionId": "0x1", "sendNetwork": "BSC",
+            "payBySig": {
+                "token": "0x2", "payee": "0x3", "amount": "10", "swapTxId": "0x4",
+                "hash": "0x5", "contractName": "FERRUM_TOKEN_BRIDGE_POOL",
+                "contractVersion": "000.003", "contractAddress": "0x6", "sourceChainId": 4,
+                "signatures": signatures,
+            },
         }
-        Ok(result)
+    }
+
+    #[test]
+    fn test_malformed_signature_is_an_error() {
+        let sig = doc! { "signature": "0x7", "creator": "0x8", "creationTime": 1i64 };
+        let wi = DatabaseClient::doc_to_withdraw_item(&item_doc(vec![sig]));
+        assert_eq!(wi.map(|wi| wi.pay_by_sig.signatures[0].creation_time), Ok(1));
+
+        let sig = doc! { "signature": "0x7", "creator": "0x8", "creationTime": 1i32 };
+        assert!(DatabaseClient::doc_to_withdraw_item(&item_doc(vec![sig])).is_err());
+        let mut d = item_doc(vec![]);
+        d.get_document_mut("payBySig").unwrap().remove("signatures");
+        assert!(DatabaseClient::doc_to_withdraw_item(&d).is_err());
     }
 }
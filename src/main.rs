@@ -1,3 +1,4 @@
+mod chain;
 mod cli;
 mod crypto;
 mod database;
@@ -8,33 +9,52 @@ mod two_fa;
 use crate::crypto::crypto_utils::CryptoUtils;
 use crate::database::mongo::DatabaseClient;
 use crate::signer::key_provider::{EnvKeyProvider, LiveConfig, SecureKeyProvider};
+use crate::signer::ledger::SignLedger;
 use crate::types::errors::{BError, BResult};
 use crate::types::types::AppConfig;
 use crate::validator::swap_processor::{Processor, SwapProcessor};
 use crate::validator::validator::{MultiSigValidator};
-use cli::cli::cli;
+use cli::cli::{cli, Command, Opt};
 use signer::service::SignerServiceImpl;
 use std::{fs};
+use std::sync::Arc;
+use std::time::Duration;
 use std::io::{self, Write};
 use crate::crypto::envelope_cryptor::EnvelopeCryptorImpl;
 use crate::crypto::local_cryptor::LocalCryptor;
 use crate::two_fa::two_fa_client::TwoFaClientImpl;
+use crate::validator::blocklist::{load_blocklist, reload_blocklist, Blocklist};
+use crate::validator::daemon::{run_daemon, run_watcher};
+use crate::validator::shutdown::{shutdown_channel, wait_for_signal, Shutdown};
 
 // MultiSigSigner. This signer just aggregates signatures for a number of other
 // signers. Once enough signatures for a message is provided, we just sign it
 // without knowing what the msg represents at all.
 
-async fn setup(c: &AppConfig, live_config: LiveConfig, insecure: bool) -> BResult<Box<dyn Processor>> {
+const EXIT_OK: i32 = 0;
+const EXIT_ERROR: i32 = 1;
+// In-flight items did not finish before the shutdown timeout. Their state is unknown
+const EXIT_SHUTDOWN_TIMEOUT: i32 = 2;
+
+async fn setup(c: &AppConfig, live_config: LiveConfig, insecure: bool, shutdown: Shutdown)
+    -> BResult<Box<dyn Processor>> {
     let cr_f = || CryptoUtils::new();
     let signer = || SignerServiceImpl::new(Box::new(cr_f()));
-    let db = DatabaseClient::new(&c.db)
+    let db = DatabaseClient::new(&c.db, &c.retry)
         .await
         .map_err(|_| BError::new("Error initializing db client"))?;
+    let ledger = SignLedger::open(&c.ledger_path)?;
+    let blocklist = Arc::new(Blocklist::default());
+    if let Some(bc) = &c.blocklist {
+        blocklist.set(load_blocklist(bc, &db).await?);
+        tokio::spawn(reload_blocklist(blocklist.clone(), bc.clone(), db.clone(), shutdown.clone()));
+    }
     match insecure {
         true => {
             let kp = EnvKeyProvider::new();
-            let v = MultiSigValidator::new(&c.signer, signer(), kp);
-            let p = SwapProcessor::new( v, db);
+            let v = MultiSigValidator::new(
+                &c.signer, &c.network_signers, signer(), kp, ledger, blocklist.clone());
+            let p = SwapProcessor::new(&c.processor, &c.retry, v, db, blocklist, shutdown);
             Ok(Box::new(p))
         },
         false => {
@@ -44,13 +64,16 @@ async fn setup(c: &AppConfig, live_config: LiveConfig, insecure: bool) -> BResul
                 double_cryptor(),
                 &c.two_fa.url,
                 &c.two_fa.hmac_public_key,
-                &c.two_fa.hmac_secret_key,);
+                &c.two_fa.hmac_secret_key,
+                c.two_fa.timeout_secs,
+                &c.retry,);
             let mut skp = SecureKeyProvider::new(
                 two_fa_client,
                 double_cryptor(), );
             skp.init(&c.enc_key, &c.two_fa.two_fa_id, live_config).await?;
-            let v = MultiSigValidator::new(&c.signer, signer(), skp);
-            let p = SwapProcessor::new(v, db);
+            let v = MultiSigValidator::new(
+                &c.signer, &c.network_signers, signer(), skp, ledger, blocklist.clone());
+            let p = SwapProcessor::new(&c.processor, &c.retry, v, db, blocklist, shutdown);
             Ok(Box::new(p))
         },
     }
@@ -68,10 +91,12 @@ pub fn get_input(prompt: &str) -> String {
     input.trim().to_string()
 }
 
-#[tokio::main]
-async fn main() {
-    let opt = cli();
-    let confs = match fs::read_to_string(&opt.config) {
+/**
+Runs the requested work. Once SIGTERM or SIGINT is received, no new item is
+taken, and the in-flight items are given the shutdown timeout to finish.
+ **/
+async fn run(opt: Opt) -> i32 {
+    let mut confs = match fs::read_to_string(&opt.config) {
         Ok(c) => AppConfig::from_str(&c),
         Err(e) => {
             println!(
@@ -79,7 +104,7 @@ async fn main() {
                 &opt.config.as_path().to_str().unwrap_or(""),
                 e
             );
-            return;
+            return EXIT_ERROR;
         }
     };
     let live_config = match opt.insecure {
@@ -92,24 +117,96 @@ async fn main() {
             two_fa: get_input("Enter Google Authenticator Token:"),
         }
     };
-    let psr = setup(&confs, live_config, opt.insecure).await;
+    confs.processor.dry_run |= opt.dry_run;
+    let networks = match opt.network.is_empty() {
+        true => confs.networks.clone(),
+        false => opt.network.clone(),
+    };
+    if networks.is_empty() {
+        println!("No network provided. Use --network or set networks in the config file");
+        return EXIT_ERROR;
+    }
+    if opt.transaction_id.is_some() && networks.len() != 1 {
+        println!("Exactly one network is required with --transaction-id");
+        return EXIT_ERROR;
+    }
+    let (trigger, shutdown) = shutdown_channel();
+    let psr = setup(&confs, live_config, opt.insecure, shutdown.clone()).await;
     let ps = match psr {
         Ok(p) => p,
         Err(e) => {
             println!("Error setting up the environment: {}", &e.msg);
-            return;
+            return EXIT_ERROR;
         }
     };
-    match ps.process_for_network(&opt.network).await {
-        Ok(r) => r,
-        Err(e) => {
-            println!(
-                "Error processing for the network: {} - {}",
-                &opt.network, e.msg
-            );
-            return;
+    let work = async {
+        if let Some(tx_id) = &opt.transaction_id {
+            return match ps.process_transaction(&networks[0], tx_id).await {
+                Ok(_) => EXIT_OK,
+                Err(e) => {
+                    println!("Error processing {}:{} - {}", &networks[0], tx_id, e.msg);
+                    EXIT_ERROR
+                }
+            };
         }
-    }
+        match &opt.cmd {
+            Some(Command::Run { interval, jitter, watch: true }) => {
+                run_watcher(
+                    ps.as_ref(),
+                    &networks,
+                    Duration::from_secs(*interval),
+                    Duration::from_secs(*jitter),
+                    shutdown.clone(),
+                ).await;
+                EXIT_OK
+            },
+            Some(Command::Run { interval, jitter, watch: false }) => {
+                run_daemon(
+                    ps.as_ref(),
+                    &networks,
+                    Duration::from_secs(*interval),
+                    Duration::from_secs(*jitter),
+                    shutdown.clone(),
+                ).await;
+                EXIT_OK
+            },
+            None => match ps.process_for_networks(&networks).await {
+                Ok(_) => EXIT_OK,
+                Err(e) => {
+                    println!("{}", e.msg);
+                    EXIT_ERROR
+                }
+            },
+        }
+    };
+    tokio::pin!(work);
+    let code = tokio::select! {
+        code = &mut work => code,
+        sig = wait_for_signal() => {
+            println!("Received {}. Waiting up to {}s for in-flight items",
+                     sig, opt.shutdown_timeout);
+            trigger.trigger();
+            match tokio::time::timeout(Duration::from_secs(opt.shutdown_timeout), &mut work).await {
+                Ok(EXIT_OK) => {
+                    println!("Stopped cleanly");
+                    EXIT_OK
+                },
+                Ok(code) => code,
+                Err(_) => {
+                    println!("Timed out waiting for in-flight items. Some signatures may not have been written");
+                    EXIT_SHUTDOWN_TIMEOUT
+                },
+            }
+        },
+    };
+    ps.wipe_key();
+    code
+}
+
+#[tokio::main]
+async fn main() {
+    let code = run(cli()).await;
+    std::process::exit(code);
 }
 
 mod test {
@@ -150,7 +247,8 @@ mod test {
         let signed = signer.sign(&msg, &sk);
 
         println!("Signed message: {}", &signed);
-        let verif_addr = signer.recover(&msg, &signed);
+        let verif_addr = signer.recover_strict(&msg, &signed).ok().expect("Recovery failed");
         println!("Verified address is: {}", &verif_addr);
+        assert_eq!(verif_addr, format!("0x{}", &address));
     }
 }
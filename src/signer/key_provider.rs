@@ -1,4 +1,6 @@
 use std::env;
+use std::sync::RwLock;
+use zeroize::Zeroize;
 use crate::BResult;
 use crate::crypto::cryptor::EncryptedData;
 use crate::crypto::envelope_cryptor::EnvelopeCryptor;
@@ -7,30 +9,42 @@ use crate::two_fa::two_fa_client::{TwoFaClient};
 
 const PRIVATE_KEY_CLEAR_TEXT: &str = "PRIVATE_KEY_CLEAR_TEXT";
 
-pub trait KeyProvider {
+pub trait KeyProvider: Send + Sync {
     fn get_sk(&self) -> String;
+    /// Overwrite the key in memory. No signature can be produced afterwards
+    fn wipe(&self);
+}
+
+fn wipe_secret(secret: &RwLock<String>) {
+    let mut s = secret.write().unwrap_or_else(|e| e.into_inner());
+    s.zeroize();
 }
 
 pub struct EnvKeyProvider {
-    sk: String,
+    sk: RwLock<String>,
 }
 
 impl EnvKeyProvider {
     pub fn new() -> Self {
         EnvKeyProvider {
-            sk: env::var(PRIVATE_KEY_CLEAR_TEXT).expect("PRIVATE_KEY_CLEAR_TEXT required"),
+            sk: RwLock::new(
+                env::var(PRIVATE_KEY_CLEAR_TEXT).expect("PRIVATE_KEY_CLEAR_TEXT required")),
         }
     }
 }
 
 impl KeyProvider for EnvKeyProvider {
     fn get_sk(&self) -> String {
-        self.sk.clone()
+        self.sk.read().unwrap().clone()
+    }
+
+    fn wipe(&self) {
+        wipe_secret(&self.sk);
     }
 }
 
 pub struct SecureKeyProvider<TFC: TwoFaClient, EC: EnvelopeCryptor> {
-    secret: String,
+    secret: RwLock<String>,
     two_fa_client: Box<TFC>,
     cryptor: Box<EC>,
 }
@@ -48,7 +62,7 @@ impl<TFC: TwoFaClient, EC: EnvelopeCryptor> SecureKeyProvider<TFC, EC> {
         SecureKeyProvider {
             two_fa_client: Box::new(two_fa_client),
             cryptor: Box::new(cryptor),
-            secret: String::new(),
+            secret: RwLock::new(String::new()),
         }
     }
     pub async fn init(&mut self, enc_key: &String, two_fa_id: &String, lc: LiveConfig, ) -> BResult<()> {
@@ -56,14 +70,19 @@ impl<TFC: TwoFaClient, EC: EnvelopeCryptor> SecureKeyProvider<TFC, EC> {
         let unwrap1 = self.two_fa_client.decrypt(two_fa_id, &lc.two_fa, &enc).await?;
         let sk = EncryptedData::from_str(&unwrap1)?;
 
-        self.secret = self.cryptor.decrypt_to_hex(
+        let secret = self.cryptor.decrypt_to_hex(
             &sk, &LocalCryptor::raw_string_to_key(&lc.pw))?;
+        *self.secret.write().unwrap() = secret;
         Ok(())
     }
 }
 
 impl <TFC: TwoFaClient, EC: EnvelopeCryptor> KeyProvider for SecureKeyProvider<TFC, EC> {
     fn get_sk(&self) -> String {
-        self.secret.clone() // TODO: Update such that secret is never passed
+        self.secret.read().unwrap().clone() // TODO: Update such that secret is never passed
+    }
+
+    fn wipe(&self) {
+        wipe_secret(&self.secret);
     }
 }
\ No newline at end of file
@@ -0,0 +1,114 @@
+use crate::types::errors::{BError, BResult};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/**
+Local append-only record of every (network, transaction id, hash) this node has
+signed, one tab separated line each. It is kept outside of the db, so that an
+altered withdraw item cannot get a second hash signed for the same transaction.
+ **/
+pub struct SignLedger {
+    path: PathBuf,
+    signed: Mutex<HashMap<(String, String), String>>,
+}
+
+impl SignLedger {
+    pub fn open(path: &Path) -> BResult<Self> {
+        let err = |e: std::io::Error| BError::new(&format!(
+            "Error reading the sign ledger {}: {}", path.display(), e));
+        let mut signed = HashMap::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(path).map_err(err)?);
+            for (i, line) in reader.lines().enumerate() {
+                let line = line.map_err(err)?;
+                match line.split('\t').collect::<Vec<&str>>().as_slice() {
+                    [network, tx, hash] => {
+                        signed.insert(
+                            (network.to_string(), tx.to_lowercase()), hash.to_lowercase());
+                    },
+                    _ => return Err(BError::new(&format!(
+                        "Corrupt sign ledger {} at line {}", path.display(), i + 1))),
+                }
+            }
+        }
+        Ok(SignLedger { path: path.to_path_buf(), signed: Mutex::new(signed) })
+    }
+
+    /// Fails if a different hash was already signed for the transaction
+    pub fn check(&self, network: &str, transaction_id: &str, hash: &str) -> BResult<()> {
+        let signed = self.signed.lock().unwrap();
+        Self::check_entry(&signed, network, transaction_id, hash).map(|_| ())
+    }
+
+    /// Whether the hash is already recorded, or an error if another one is
+    fn check_entry(signed: &HashMap<(String, String), String>, network: &str,
+                   transaction_id: &str, hash: &str) -> BResult<bool> {
+        let key = (String::from(network), transaction_id.to_lowercase());
+        match signed.get(&key) {
+            Some(h) if h.eq_ignore_ascii_case(hash) => Ok(true),
+            Some(h) => Err(BError::new(&format!(
+                "Already signed hash {} for {}:{}. Refusing to sign {}",
+                h, network, transaction_id, hash))),
+            None => Ok(false),
+        }
+    }
+
+    /**
+    Records the hash as signed for the transaction. Fails if a different hash was
+    already signed for it. The entry is flushed to disk, on a blocking thread,
+    before returning.
+     **/
+    pub async fn record(&self, network: &str, transaction_id: &str, hash: &str) -> BResult<()> {
+        let key = (String::from(network), transaction_id.to_lowercase());
+        {
+            let mut signed = self.signed.lock().unwrap();
+            if Self::check_entry(&signed, network, transaction_id, hash)? {
+                return Ok(());
+            }
+            // Taken now, so that a concurrent record of another hash fails
+            signed.insert(key.clone(), hash.to_lowercase());
+        }
+        let path = self.path.clone();
+        let line = format!("{}\t{}\t{}\n", network, &key.1, hash.to_lowercase());
+        let res = tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+            let mut f = OpenOptions::new().create(true).append(true).open(&path)?;
+            f.write_all(line.as_bytes())?;
+            f.sync_all()
+        }).await;
+        let err = match res {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) => e.to_string(),
+            Err(e) => e.to_string(),
+        };
+        self.signed.lock().unwrap().remove(&key);
+        Err(BError::new(&format!(
+            "Error writing the sign ledger {}: {}", self.path.display(), err)))
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::SignLedger;
+    #[allow(unused_imports)]
+    use crate::crypto::crypto_utils::rand_hex;
+
+    #[tokio::test]
+    async fn test_conflicting_hash_is_refused() {
+        let path = std::env::temp_dir().join(format!("sign-ledger-{}", rand_hex(8)));
+        let ledger = SignLedger::open(&path).ok().unwrap();
+        assert!(ledger.record("RINKEBY", "0x1", "0xaa").await.is_ok());
+        assert!(ledger.record("RINKEBY", "0x1", "0xAA").await.is_ok());
+        assert!(ledger.record("RINKEBY", "0x1", "0xbb").await.is_err());
+        assert!(ledger.check("RINKEBY", "0x1", "0xbb").is_err());
+        assert!(ledger.check("BSC", "0x2", "0xbb").is_ok());
+        assert!(ledger.record("BSC", "0x1", "0xbb").await.is_ok());
+        // Survives a restart
+        let ledger = SignLedger::open(&path).ok().unwrap();
+        assert!(ledger.record("RINKEBY", "0X1", "0xbb").await.is_err());
+        assert!(ledger.record("RINKEBY", "0x1", "0xaa").await.is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
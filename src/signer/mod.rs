@@ -1,2 +1,3 @@
 pub mod key_provider;
+pub mod ledger;
 pub mod service;
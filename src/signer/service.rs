@@ -1,33 +1,16 @@
-use crate::crypto::crypto_utils::{b2h, h2b, CryptoUtils};
+use crate::crypto::crypto_utils::{b2h, decode_hex, h2b, CryptoUtils};
+use crate::types::errors::{BError, BResult};
 
 pub trait SignerService {
     fn sign(&self, msg: &String, sk: &String) -> String;
-    fn recover(&self, msg: &String, sig: &String) -> String;
+    /// Fails on malformed or non-canonical (high s, unexpected v) signatures
+    fn recover_strict(&self, msg: &str, sig: &str) -> BResult<String>;
 }
 
 pub struct SignerServiceImpl {
     cr: Box<CryptoUtils>,
 }
 
-fn v_to_standard(sig: &String) -> String {
-    let mut trimmed = sig.chars();
-    trimmed.by_ref().nth(sig.len() - 3); // Move to the last two char position
-    let suffix = trimmed.as_str();
-    if suffix.eq("1b") {
-        let mut rv = sig.clone();
-        rv.pop(); rv.pop();
-        rv.push('0'); rv.push('0');
-        rv
-    } else if suffix.eq("1c") {
-        let mut rv = sig.clone();
-        rv.pop(); rv.pop();
-        rv.push('0'); rv.push('1');
-        rv
-    } else {
-        sig.to_owned()
-    }
-}
-
 fn v_to_smart_contract(sig: &String) -> String {
     let mut trimmed = sig.chars();
     trimmed.by_ref().nth(sig.len() - 3); // Move to the last two char position
@@ -62,10 +45,11 @@ impl SignerService for SignerServiceImpl {
         v_to_smart_contract(&b2h(sig.as_slice()))
     }
 
-    fn recover(&self, msg: &String, sig: &String) -> String {
-        let h = h2b(msg);
-        let sig_v = h2b(&v_to_standard(sig));
-        let address = self.cr.recover(&h, &sig_v);
-        format!("0x{}", b2h(&address))
+    fn recover_strict(&self, msg: &str, sig: &str) -> BResult<String> {
+        let h = decode_hex(msg)?;
+        let sig_b = decode_hex(sig)?;
+        let address = self.cr.recover_strict(&h, &sig_b)
+            .map_err(|e| BError::new(&format!("Invalid signature '{}': {}", sig, e)))?;
+        Ok(format!("0x{}", b2h(&address)))
     }
 }
@@ -6,6 +6,9 @@ use crate::crypto::hmac::calculate_hmac_auth;
 use async_trait::async_trait;
 use serde::de::DeserializeOwned;
 use crate::crypto::envelope_cryptor::EnvelopeCryptor;
+use crate::types::retry::with_retry;
+use crate::types::types::RetryConfig;
+use std::time::Duration;
 
 #[derive(Serialize, Deserialize)]
 struct NewTwoFaResponse {
@@ -19,11 +22,15 @@ pub struct TwoFaWrappedData {
 }
 
 fn req_west_err_map(e: reqwest::Error) -> BError {
-    BError::new(
-        &format!("Error requesting '{}': {}",
+    let msg = format!("Error requesting '{}': {}",
         e.url().map(|u| u.as_str()).unwrap_or(""),
         e.to_string(),
-        ))
+        );
+    // Could not reach the server, or the server is temporarily failing
+    match e.is_timeout() || e.is_connect() || e.status().is_some_and(|s| s.is_server_error()) {
+        true => BError::transient(&msg),
+        false => BError::new(&msg),
+    }
 }
 
 async fn json_rpc<T: DeserializeOwned>(
@@ -31,14 +38,20 @@ async fn json_rpc<T: DeserializeOwned>(
     body: &String,
     hmac_public_key: &String,
     hmac_secret_key: &String,
+    timeout: Duration,
 ) -> BResult<T> {
     let client = reqwest::Client::new();
     let hmac_header = calculate_hmac_auth(url, body, hmac_public_key, hmac_secret_key);
     let res = client.post(url)
+         .timeout(timeout)
          .body(body.clone())
          .header("X-Authorization", hmac_header)
          .send()
         .await
+        .and_then(|r| match r.status().is_server_error() {
+            true => r.error_for_status(),
+            false => Ok(r),
+        })
         .map_err(req_west_err_map)?;
     let j_res: T = res.json()
         .await
@@ -46,8 +59,8 @@ async fn json_rpc<T: DeserializeOwned>(
     Ok(j_res)
 }
 
-#[async_trait(?Send)]
-pub trait TwoFaClient {
+#[async_trait]
+pub trait TwoFaClient: Send + Sync {
     async fn get_two_fa_wrapped_data(
         &self,
         two_fa_id: &String,
@@ -67,21 +80,26 @@ pub struct TwoFaClientImpl<EC> {
     url: String,
     hmac_public_key: String,
     hmac_private_key: String,
+    timeout: Duration,
+    retry: RetryConfig,
 }
 
 impl <EC: EnvelopeCryptor> TwoFaClientImpl<EC> {
     pub fn new(cryptor: EC,
-               url: &String, hmac_public_key: &String, hmac_private_key: &String) -> Self {
+               url: &String, hmac_public_key: &String, hmac_private_key: &String,
+               timeout_secs: u64, retry: &RetryConfig) -> Self {
         TwoFaClientImpl {
             cryptor: Box::new(cryptor),
             hmac_private_key: hmac_private_key.clone(),
             hmac_public_key: hmac_public_key.clone(),
             url: url.clone(),
+            timeout: Duration::from_secs(timeout_secs),
+            retry: retry.clone(),
         }
     }
 }
 
-#[async_trait(?Send)]
+#[async_trait]
 impl <EC: EnvelopeCryptor> TwoFaClient for TwoFaClientImpl<EC> {
     async fn get_two_fa_wrapped_data(&self,
         two_fa_id: &String,
@@ -95,12 +113,14 @@ impl <EC: EnvelopeCryptor> TwoFaClient for TwoFaClientImpl<EC> {
             },
             "params": [],
         });
-        Ok(json_rpc(
+        let body = req.to_string();
+        with_retry(&self.retry, "getTwoFaWrappedData", |e: &BError| e.transient, || json_rpc(
             &self.url,
-            &req.to_string(),
+            &body,
             &self.hmac_public_key,
-            &self.hmac_private_key)
-            .await?)
+            &self.hmac_private_key,
+            self.timeout))
+            .await
     }
 
     async fn decrypt(
@@ -124,3 +144,33 @@ impl <EC: EnvelopeCryptor> TwoFaClient for TwoFaClientImpl<EC> {
             }, &wrapper_key.secret)
     }
 }
+
+mod test {
+    #[allow(unused_imports)]
+    use super::{json_rpc, TwoFaWrappedData};
+    #[allow(unused_imports)]
+    use std::time::Duration;
+    #[allow(unused_imports)]
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_hung_server_times_out() {
+        // Accepts the connections, never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut sockets = vec![];
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                sockets.push(socket);
+            }
+        });
+        let key = String::from("0102");
+        let res = tokio::time::timeout(
+            Duration::from_secs(10),
+            json_rpc::<TwoFaWrappedData>(&url, &String::from("{}"), &key, &key, Duration::from_secs(1)))
+            .await;
+        let e = res.expect("Must time out").err().expect("Must fail");
+        assert!(e.transient, "A timeout must be retried");
+    }
+}
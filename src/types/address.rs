@@ -0,0 +1,87 @@
+use crate::crypto::crypto_utils::{b2h, keccak256_hash};
+use crate::types::errors::{BError, BResult};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+
+/**
+An EVM address. Any casing is accepted, but a mixed case address must carry a
+valid EIP-55 checksum. Displayed as lowercase `0x…`.
+ **/
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Address([u8; 20]);
+
+impl Address {
+    pub fn parse(s: &str) -> BResult<Address> {
+        let err = |reason: &str| BError::new(&format!("Invalid address '{}': {}", s, reason));
+        let h = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+        if h.len() != 40 {
+            return Err(err("expected 20 bytes"));
+        }
+        let bytes = hex::decode(h).map_err(|_| err("not hex"))?;
+        let mut a = [0u8; 20];
+        a.copy_from_slice(&bytes);
+        let address = Address(a);
+        let mixed_case = h.chars().any(|c| c.is_ascii_lowercase())
+            && h.chars().any(|c| c.is_ascii_uppercase());
+        if mixed_case && address.to_checksum()[2..].ne(h) {
+            return Err(err("bad EIP-55 checksum"));
+        }
+        Ok(address)
+    }
+
+    /// EIP-55 mixed case encoding
+    pub fn to_checksum(self) -> String {
+        let lower = b2h(&self.0);
+        let hash = keccak256_hash(lower.as_bytes());
+        let checksummed: String = lower
+            .chars()
+            .enumerate()
+            .map(|(i, c)| {
+                let nibble = (hash[i / 2] >> (if i % 2 == 0 { 4 } else { 0 })) & 0x0f;
+                if nibble >= 8 { c.to_ascii_uppercase() } else { c }
+            })
+            .collect();
+        format!("0x{}", checksummed)
+    }
+}
+
+impl TryFrom<String> for Address {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Address::parse(&s).map_err(|e| e.msg)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{}", b2h(&self.0))
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::Address;
+
+    #[test]
+    fn test_eip55() {
+        let checksummed = [
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+            "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+            "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+        ];
+        for c in checksummed {
+            let a = Address::parse(c).ok().expect("Valid checksum rejected");
+            assert_eq!(a.to_checksum(), c);
+            assert_eq!(a.to_string(), c.to_lowercase());
+            assert!(Address::parse(&c.to_lowercase()).ok() == Some(a));
+            assert!(Address::parse(&c[2..].to_uppercase()).ok() == Some(a));
+        }
+        assert!(Address::parse("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD").is_err());
+        assert!(Address::parse("0x5aaeb6053f3e94c9b9a09f33669435e7ef1bea").is_err());
+        assert!(Address::parse("0x5aaeb6053f3e94c9b9a09f33669435e7ef1beazz").is_err());
+    }
+}
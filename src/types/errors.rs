@@ -1,13 +1,31 @@
+use std::fmt;
+
 pub struct BError {
     pub msg: String,
+    /// The operation may succeed if retried
+    pub transient: bool,
 }
 
 impl BError {
     pub fn new(msg: &str) -> Self {
         BError {
             msg: String::from(msg),
+            transient: false,
+        }
+    }
+
+    pub fn transient(msg: &str) -> Self {
+        BError {
+            msg: String::from(msg),
+            transient: true,
         }
     }
 }
 
 pub type BResult<T> = Result<T, BError>;
+
+impl fmt::Display for BError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", &self.msg)
+    }
+}
@@ -1,3 +1,5 @@
+pub mod address;
 pub mod errors;
+pub mod retry;
 pub mod types;
 pub mod utils;
@@ -0,0 +1,115 @@
+use crate::types::types::RetryConfig;
+use rand::{thread_rng, Rng};
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+
+/**
+Delay before the attempt after `attempt` (starting at 1). Doubles every time, up to
+the max delay. Jitter is not included.
+ **/
+pub fn backoff_delay(policy: &RetryConfig, attempt: u32) -> Duration {
+    let factor = 2u64.saturating_pow(attempt.saturating_sub(1));
+    let delay = policy.initial_delay_ms.saturating_mul(factor);
+    Duration::from_millis(delay.min(policy.max_delay_ms))
+}
+
+fn jitter(policy: &RetryConfig) -> Duration {
+    match policy.max_jitter_ms {
+        0 => Duration::from_millis(0),
+        max => Duration::from_millis(thread_rng().gen_range(0..=max)),
+    }
+}
+
+/**
+Run the operation until it succeeds, fails with a permanent error, or the max
+attempts are used. Only errors for which `is_transient` returns true are retried.
+ **/
+pub async fn with_retry<T, E, F, Fut>(
+    policy: &RetryConfig,
+    name: &str,
+    is_transient: fn(&E) -> bool,
+    mut op: F,
+) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt: u32 = 1;
+    loop {
+        match op().await {
+            Ok(r) => return Ok(r),
+            Err(e) => {
+                if attempt >= policy.max_attempts || !is_transient(&e) {
+                    return Err(e);
+                }
+                let wait = backoff_delay(policy, attempt) + jitter(policy);
+                println!(
+                    "{} failed (attempt {} of {}): {}. Retrying in {:?}",
+                    name, attempt, policy.max_attempts, e, wait
+                );
+                tokio::time::sleep(wait).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::{backoff_delay, with_retry};
+    #[allow(unused_imports)]
+    use crate::types::types::RetryConfig;
+    #[allow(unused_imports)]
+    use std::sync::atomic::{AtomicU32, Ordering};
+    #[allow(unused_imports)]
+    use std::time::Duration;
+
+    #[allow(dead_code)]
+    fn policy() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 4,
+            initial_delay_ms: 1,
+            max_delay_ms: 3,
+            max_jitter_ms: 0,
+        }
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let p = policy();
+        assert_eq!(backoff_delay(&p, 1), Duration::from_millis(1));
+        assert_eq!(backoff_delay(&p, 2), Duration::from_millis(2));
+        assert_eq!(backoff_delay(&p, 3), Duration::from_millis(3));
+        assert_eq!(backoff_delay(&p, 100), Duration::from_millis(3));
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_errors_only() {
+        let calls = AtomicU32::new(0);
+        let res: Result<(), String> = with_retry(&policy(), "transient", |_| true, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(String::from("Timeout"))
+        }).await;
+        assert!(res.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 4, "Must use all attempts");
+
+        let calls = AtomicU32::new(0);
+        let res: Result<(), String> = with_retry(&policy(), "permanent", |_| false, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(String::from("Bad token"))
+        }).await;
+        assert!(res.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1, "Permanent errors must not be retried");
+
+        let calls = AtomicU32::new(0);
+        let res: Result<u32, String> = with_retry(&policy(), "recovers", |_| true, || async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(String::from("Timeout")),
+                n => Ok(n),
+            }
+        }).await;
+        assert_eq!(res.ok(), Some(1));
+    }
+}
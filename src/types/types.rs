@@ -1,7 +1,11 @@
-use serde::Deserialize;
-use serde::__private::Formatter;
+use serde::{Deserialize, Deserializer};
+use std::fmt::Formatter;
 use serde_json;
 use std::fmt;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use crate::types::address::Address;
 
 #[derive(Clone, Debug)]
 pub struct WithdrawItemSignature {
@@ -17,6 +21,10 @@ impl fmt::Display for WithdrawItemSignature {
 }
 
 pub struct PayBySig {
+    pub token: String,
+    pub payee: String,
+    /// Raw amount, as a decimal string
+    pub amount: String,
     pub source_chain_id: i32,
     pub swap_tx_id: String,
     pub contract_name: String,
@@ -36,6 +44,28 @@ pub struct WithdrawItem {
     pub signatures: i32,
 }
 
+impl fmt::Display for WithdrawItem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{} from {} (v: {}, signatures: {}) swap_tx_id: {}, source_chain_id: {}, \
+            contract: {}@{} {}, hash: {}",
+            &self.receive_network,
+            &self.receive_transaction_id,
+            &self.send_network,
+            &self.v,
+            &self.signatures,
+            &self.pay_by_sig.swap_tx_id,
+            &self.pay_by_sig.source_chain_id,
+            &self.pay_by_sig.contract_name,
+            &self.pay_by_sig.contract_version,
+            &self.pay_by_sig.contract_address,
+            &self.pay_by_sig.hash
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct SignedSwap {
     pub creation_time: i64,
     pub network: String,
@@ -60,12 +90,151 @@ impl fmt::Display for SignedSwap {
     }
 }
 
+/**
+Why a withdraw item was, or was not signed. Saved for the backend and support.
+ **/
+#[derive(Clone, Debug, Default)]
+pub struct ValidationReport {
+    pub network: String,
+    pub transaction_id: String,
+    /// Lease owner id of the node that made the report
+    pub node: String,
+    pub outcome: String,
+    pub reason: String,
+    pub signatures: usize,
+    pub valid_weight: u64,
+    pub required_weight: u64,
+    /// Rejected validator signatures, as (signer, reason)
+    pub rejected: Vec<(String, String)>,
+    pub creation_time: i64,
+}
+
+/**
+A validator can be configured as a plain address, or as an object with a weight
+and an optional validity window.
+ **/
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum ValidatorConfigRaw {
+    Address(String),
+    #[serde(rename_all = "camelCase")]
+    Full {
+        address: String,
+        #[serde(default = "default_validator_weight")]
+        weight: u64,
+        valid_from: Option<i64>,
+        valid_until: Option<i64>,
+    },
+}
+
+fn default_validator_weight() -> u64 {
+    1
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "ValidatorConfigRaw")]
+pub struct ValidatorConfig {
+    pub address: Address,
+    pub weight: u64,
+    /// Signatures created before this time (unix ms) are not accepted
+    pub valid_from: Option<i64>,
+    /// Signatures created after this time (unix ms) are not accepted
+    pub valid_until: Option<i64>,
+}
+
+impl ValidatorConfig {
+    pub fn is_active_at(&self, time: i64) -> bool {
+        self.valid_from.is_none_or(|from| time >= from)
+            && self.valid_until.is_none_or(|until| time <= until)
+    }
+}
+
+impl TryFrom<ValidatorConfigRaw> for ValidatorConfig {
+    type Error = String;
+
+    fn try_from(raw: ValidatorConfigRaw) -> Result<Self, Self::Error> {
+        let parse = |a: &str| Address::parse(a).map_err(|e| e.msg);
+        Ok(match raw {
+            ValidatorConfigRaw::Address(address) => ValidatorConfig {
+                address: parse(&address)?,
+                weight: default_validator_weight(),
+                valid_from: None,
+                valid_until: None,
+            },
+            ValidatorConfigRaw::Full { address, weight, valid_from, valid_until } =>
+                ValidatorConfig { address: parse(&address)?, weight, valid_from, valid_until },
+        })
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SignerConfig {
-    pub address: String,
-    pub validators: Vec<String>,
+    pub address: Address,
+    pub validators: Vec<ValidatorConfig>,
+    #[serde(deserialize_with = "non_zero")]
     pub min_threshold: u32,
+    /// Min total weight of the valid signers. Defaults to `min_threshold`
+    #[serde(default, deserialize_with = "non_zero_opt")]
+    pub min_weight: Option<u64>,
+    /// Validator signatures older than this are not accepted
+    pub max_signature_age_secs: Option<u64>,
+    /// How far in the future a validator signature may be dated
+    pub max_clock_skew_secs: Option<u64>,
+}
+
+impl SignerConfig {
+    pub fn required_weight(&self) -> u64 {
+        self.min_weight.unwrap_or(self.min_threshold as u64)
+    }
+
+    /// Weight of the signer. Zero if it is not a configured validator
+    pub fn weight_of(&self, signer: &Address) -> u64 {
+        self.validators
+            .iter()
+            .filter(|v| v.address.eq(signer))
+            .map(|v| v.weight)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The entry for the signer that is active at the given time, if any
+    pub fn active_validator(&self, signer: &Address, time: i64) -> Option<&ValidatorConfig> {
+        self.validators
+            .iter()
+            .filter(|v| v.address.eq(signer) && v.is_active_at(time))
+            .max_by_key(|v| v.weight)
+    }
+
+    /// This config with the fields set in the override replaced
+    pub fn with_override(&self, o: &SignerOverride) -> SignerConfig {
+        SignerConfig {
+            address: self.address,
+            validators: o.validators.clone().unwrap_or_else(|| self.validators.clone()),
+            min_threshold: o.min_threshold.unwrap_or(self.min_threshold),
+            min_weight: match o.min_threshold.is_some() || o.min_weight.is_some() {
+                true => o.min_weight,
+                false => self.min_weight,
+            },
+            max_signature_age_secs: self.max_signature_age_secs,
+            max_clock_skew_secs: self.max_clock_skew_secs,
+        }
+    }
+}
+
+/**
+Per-network signer settings. Fields that are not set fall back to the top level signer.
+Overriding `minThreshold` alone also resets `minWeight` to it. The signer address
+cannot be overridden, as the node signs with a single key on all the networks.
+ **/
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SignerOverride {
+    pub validators: Option<Vec<ValidatorConfig>>,
+    #[serde(default, deserialize_with = "non_zero_opt")]
+    pub min_threshold: Option<u32>,
+    #[serde(default, deserialize_with = "non_zero_opt")]
+    pub min_weight: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -82,15 +251,220 @@ pub struct TwoFaConfig {
     pub two_fa_id: String,
     pub hmac_public_key: String,
     pub hmac_secret_key: String,
+    /// Timeout of each request to the 2FA server
+    #[serde(default = "default_rpc_timeout_secs", deserialize_with = "non_zero_secs")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ProcessorConfig {
+    /// Max number of withdraw items processed at the same time
+    pub parallelism: usize,
+    /// Validate and sign, but never write the signature to the db
+    pub dry_run: bool,
+    /// Coordinate redundant nodes with a lease per withdraw item
+    pub lease: Option<LeaseConfig>,
+    /// Allowed bridge contracts by receive network. Any contract is allowed if empty
+    pub contracts: HashMap<String, Vec<ContractConfig>>,
+    /// Rolling window caps on the signed amounts
+    pub caps: Vec<VolumeCapConfig>,
+    /// Signers whose signatures count towards `super_threshold`. Anyone if empty
+    pub super_signers: Vec<Address>,
+    /// Number of super signatures a withdraw item needs
+    #[serde(deserialize_with = "non_zero")]
+    pub super_threshold: u32,
+    /// RPC endpoints by the network the swaps are sent on, to confirm them. No
+    /// check if empty
+    pub chains: HashMap<String, ChainRpcConfig>,
+}
+
+impl Default for ProcessorConfig {
+    fn default() -> Self {
+        ProcessorConfig {
+            parallelism: 1,
+            dry_run: false,
+            lease: None,
+            contracts: HashMap::new(),
+            caps: vec![],
+            super_signers: vec![],
+            super_threshold: 1,
+            chains: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChainRpcConfig {
+    pub url: String,
+    /// Chain id the endpoint must report
+    pub chain_id: u64,
+    /// The swap transaction must have a log from this contract
+    pub bridge_contract: Address,
+    /// If set, the log's first topic must be this event signature hash
+    pub swap_event_topic: Option<String>,
+    #[serde(default)]
+    pub min_confirmations: u64,
+    /// Timeout of each RPC request
+    #[serde(default = "default_rpc_timeout_secs", deserialize_with = "non_zero_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_rpc_timeout_secs() -> u64 {
+    10
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContractConfig {
+    pub chain_id: i32,
+    pub address: String,
+    pub name: String,
+    pub version: String,
+}
+
+impl ContractConfig {
+    pub fn matches(&self, pbs: &PayBySig) -> bool {
+        self.chain_id == pbs.source_chain_id
+            && self.address.eq_ignore_ascii_case(&pbs.contract_address)
+            && self.name.eq(&pbs.contract_name)
+            && self.version.eq(&pbs.contract_version)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VolumeCapConfig {
+    pub network: String,
+    pub token: Address,
+    pub window_secs: u64,
+    /// Raw amount, as a decimal string
+    #[serde(deserialize_with = "decimal_amount")]
+    pub max_amount: u128,
+}
+
+fn decimal_amount<'de, D: Deserializer<'de>>(d: D) -> Result<u128, D::Error> {
+    let s = String::deserialize(d)?;
+    s.parse().map_err(|_| serde::de::Error::custom(format!("Invalid amount '{}'", &s)))
+}
+
+/// A threshold or a weight. 0 would be met without any valid signature
+fn non_zero<'de, D: Deserializer<'de>, T>(d: D) -> Result<T, D::Error>
+    where T: Deserialize<'de> + Default + PartialEq {
+    let v = T::deserialize(d)?;
+    match v == T::default() {
+        true => Err(serde::de::Error::custom("must be at least 1")),
+        false => Ok(v),
+    }
+}
+
+fn non_zero_opt<'de, D: Deserializer<'de>, T>(d: D) -> Result<Option<T>, D::Error>
+    where T: Deserialize<'de> + Default + PartialEq {
+    match Option::<T>::deserialize(d)? {
+        Some(v) if v == T::default() => Err(serde::de::Error::custom("must be at least 1")),
+        v => Ok(v),
+    }
+}
+
+/// A period in seconds. 0 would turn the loops using it into busy loops
+fn non_zero_secs<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+    match u64::deserialize(d)? {
+        0 => Err(serde::de::Error::custom("must be at least 1 second")),
+        secs => Ok(secs),
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LeaseConfig {
+    #[serde(deserialize_with = "non_zero_secs")]
+    pub ttl_secs: u64,
+    /// Unique id of this node. A random one is used if not provided
+    pub owner: Option<String>,
+}
+
+impl Default for LeaseConfig {
+    fn default() -> Self {
+        LeaseConfig {
+            ttl_secs: 60,
+            owner: None,
+        }
+    }
+}
+
+/**
+Where the blocked transactions, recipients and validators are read from. Both
+sources are merged, and reloaded every `reload_secs`.
+ **/
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct BlocklistConfig {
+    /// JSON file with `transactions`, `recipients` and `validators` lists
+    pub file: Option<PathBuf>,
+    /// Also read the `withdrawblocklist` collection
+    pub mongo: bool,
+    #[serde(deserialize_with = "non_zero_secs")]
+    pub reload_secs: u64,
+}
+
+impl Default for BlocklistConfig {
+    fn default() -> Self {
+        BlocklistConfig {
+            file: None,
+            mongo: false,
+            reload_secs: 30,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RetryConfig {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// Max random delay added to every wait
+    pub max_jitter_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 3,
+            initial_delay_ms: 200,
+            max_delay_ms: 5000,
+            max_jitter_ms: 100,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AppConfig {
     pub signer: SignerConfig,
+    /// Signer overrides keyed by the receive network
+    #[serde(default)]
+    pub network_signers: HashMap<String, SignerOverride>,
     pub db: DbConfig,
     pub two_fa: TwoFaConfig,
     pub enc_key: String,
+    #[serde(default)]
+    pub networks: Vec<String>,
+    #[serde(default)]
+    pub processor: ProcessorConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// Local record of the signed hashes
+    #[serde(default = "default_ledger_path")]
+    pub ledger_path: PathBuf,
+    #[serde(default)]
+    pub blocklist: Option<BlocklistConfig>,
+}
+
+fn default_ledger_path() -> PathBuf {
+    PathBuf::from("sign_ledger.tsv")
 }
 
 impl AppConfig {
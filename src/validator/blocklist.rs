@@ -0,0 +1,203 @@
+use super::shutdown::Shutdown;
+use crate::database::mongo::Database;
+use crate::types::address::Address;
+use crate::types::errors::{BError, BResult};
+use crate::types::types::BlocklistConfig;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct BlockedEntries {
+    /// Lowercase receive or swap transaction ids
+    pub transactions: HashSet<String>,
+    pub recipients: HashSet<Address>,
+    pub validators: HashSet<Address>,
+}
+
+/// Parses the address without checking its checksum. A mistyped casing must not
+/// let a blocked address through
+fn parse_any_case(address: &str) -> BResult<Address> {
+    Address::parse(&address.to_lowercase())
+}
+
+impl BlockedEntries {
+    /// Adds an entry of the given kind: transaction, recipient or validator
+    pub fn add(&mut self, kind: &str, value: &str) -> BResult<()> {
+        match kind {
+            "transaction" => {
+                self.transactions.insert(value.to_lowercase());
+            },
+            "recipient" => {
+                self.recipients.insert(parse_any_case(value)?);
+            },
+            "validator" => {
+                self.validators.insert(parse_any_case(value)?);
+            },
+            _ => return Err(BError::new(&format!("Unknown blocklist entry kind '{}'", kind))),
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.transactions.len() + self.recipients.len() + self.validators.len()
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct BlocklistFile {
+    transactions: Vec<String>,
+    recipients: Vec<String>,
+    validators: Vec<String>,
+}
+
+/**
+Withdrawals, recipients and validators that must not be acted upon. Shared by
+the processor and the validator, and replaced as a whole on reload.
+ **/
+#[derive(Default)]
+pub struct Blocklist {
+    entries: RwLock<BlockedEntries>,
+}
+
+impl Blocklist {
+    pub fn set(&self, entries: BlockedEntries) {
+        *self.entries.write().unwrap() = entries;
+    }
+
+    pub fn is_transaction_blocked(&self, transaction_id: &str) -> bool {
+        self.entries.read().unwrap().transactions.contains(&transaction_id.to_lowercase())
+    }
+
+    /// A recipient that is not an address is treated as blocked
+    pub fn is_recipient_blocked(&self, recipient: &str) -> bool {
+        parse_any_case(recipient)
+            .map_or(true, |a| self.entries.read().unwrap().recipients.contains(&a))
+    }
+
+    pub fn is_validator_blocked(&self, validator: &Address) -> bool {
+        self.entries.read().unwrap().validators.contains(validator)
+    }
+}
+
+/**
+Reads a JSON file with `transactions`, `recipients` and `validators` lists.
+ **/
+pub fn read_blocklist_file(path: &Path) -> BResult<BlockedEntries> {
+    let err = |e: String| BError::new(&format!(
+        "Error reading the blocklist {}: {}", path.display(), e));
+    let content = fs::read_to_string(path).map_err(|e| err(e.to_string()))?;
+    let f: BlocklistFile = serde_json::from_str(&content).map_err(|e| err(e.to_string()))?;
+    let mut entries = BlockedEntries::default();
+    for (kind, values) in [
+        ("transaction", &f.transactions),
+        ("recipient", &f.recipients),
+        ("validator", &f.validators),
+    ] {
+        for v in values {
+            entries.add(kind, v).map_err(|e| err(e.msg))?;
+        }
+    }
+    Ok(entries)
+}
+
+/**
+Loads the entries of the file and of the db collection, if configured. Malformed
+db entries are skipped, while a malformed file fails the load.
+ **/
+pub async fn load_blocklist<D: Database>(config: &BlocklistConfig, db: &D)
+    -> BResult<BlockedEntries> {
+    let mut entries = match &config.file {
+        Some(path) => read_blocklist_file(path)?,
+        None => BlockedEntries::default(),
+    };
+    if config.mongo {
+        let docs = db
+            .blocklist_entries()
+            .await
+            .map_err(|e| BError::new(&format!("Error loading the blocklist from db: {}", e)))?;
+        for (kind, value) in docs {
+            if let Err(e) = entries.add(&kind, &value) {
+                println!("Ignoring a blocklist entry - {}", e.msg);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/**
+Reloads the blocklist every `reload_secs` until shutdown. If loading fails, the
+current entries are kept.
+ **/
+pub async fn reload_blocklist<D: Database>(
+    blocklist: Arc<Blocklist>,
+    config: BlocklistConfig,
+    db: D,
+    mut shutdown: Shutdown,
+) {
+    loop {
+        tokio::select! {
+            _ = shutdown.requested() => break,
+            _ = tokio::time::sleep(Duration::from_secs(config.reload_secs)) => {},
+        }
+        match load_blocklist(&config, &db).await {
+            Ok(entries) => {
+                if *blocklist.entries.read().unwrap() != entries {
+                    println!("Blocklist updated. {} entries", entries.len());
+                    blocklist.set(entries);
+                }
+            },
+            Err(e) => println!("Error reloading the blocklist. Keeping the current one - {}", e.msg),
+        }
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::{read_blocklist_file, Blocklist};
+    #[allow(unused_imports)]
+    use crate::crypto::crypto_utils::rand_hex;
+    #[allow(unused_imports)]
+    use crate::types::address::Address;
+    #[allow(unused_imports)]
+    use crate::types::types::BlocklistConfig;
+
+    #[test]
+    fn test_read_blocklist_file() {
+        let path = std::env::temp_dir().join(format!("blocklist-{}.json", rand_hex(8)));
+        std::fs::write(&path, r#"{
+            "transactions": ["0xABC"],
+            "recipients": ["0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"]
+        }"#).unwrap();
+        let blocklist = Blocklist::default();
+        blocklist.set(read_blocklist_file(&path).ok().expect("Reading failed"));
+        assert!(blocklist.is_transaction_blocked("0xabc"));
+        assert!(!blocklist.is_transaction_blocked("0xabd"));
+        assert!(blocklist.is_recipient_blocked("0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed"));
+        assert!(blocklist.is_recipient_blocked("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD"),
+                "A bad checksum must not unblock the recipient");
+        assert!(blocklist.is_recipient_blocked("not an address"), "Must fail closed");
+        assert!(!blocklist.is_recipient_blocked("0x0000000000000000000000000000000000000003"));
+        let validator = Address::parse("0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed").ok().unwrap();
+        assert!(!blocklist.is_validator_blocked(&validator));
+
+        std::fs::write(&path, r#"{"validators": ["0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD"]}"#)
+            .unwrap();
+        blocklist.set(read_blocklist_file(&path).ok().expect("Reading failed"));
+        assert!(blocklist.is_validator_blocked(&validator), "A bad checksum must still block");
+        std::fs::write(&path, r#"{"validators": ["0x5aaeb6"]}"#).unwrap();
+        assert!(read_blocklist_file(&path).is_err(), "A bad address must fail the load");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_secs() {
+        let config: BlocklistConfig = serde_json::from_str(r#"{"mongo": true}"#).unwrap();
+        assert_eq!(config.reload_secs, 30);
+        assert!(serde_json::from_str::<BlocklistConfig>(r#"{"reloadSecs": 0}"#).is_err());
+    }
+}
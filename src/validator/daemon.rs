@@ -0,0 +1,112 @@
+use super::shutdown::Shutdown;
+use super::swap_processor::{ItemOutcome, Processor};
+use crate::database::mongo::WithdrawItemChanges;
+use futures::StreamExt;
+use rand::{thread_rng, Rng};
+use std::time::Duration;
+use tokio::time::Instant;
+
+fn next_wait(interval: Duration, jitter: Duration) -> Duration {
+    let jitter_ms = jitter.as_millis() as u64;
+    if jitter_ms == 0 {
+        return interval;
+    }
+    interval + Duration::from_millis(thread_rng().gen_range(0..=jitter_ms))
+}
+
+/**
+Runs the processor for the networks until shutdown is requested.
+The key provider and the db client are set up once by the caller and reused
+for every round. Errors in a round are logged and the next round is scheduled.
+ **/
+pub async fn run_daemon(
+    processor: &dyn Processor,
+    networks: &[String],
+    interval: Duration,
+    jitter: Duration,
+    mut shutdown: Shutdown,
+) {
+    println!(
+        "Running for networks {:?} every {:?} (jitter up to {:?})",
+        networks, interval, jitter
+    );
+    loop {
+        if let Err(e) = processor.process_for_networks(networks).await {
+            println!("{}", e.msg);
+        }
+        let wait = next_wait(interval, jitter);
+        tokio::select! {
+            _ = shutdown.requested() => break,
+            _ = tokio::time::sleep(wait) => {},
+        }
+    }
+}
+
+async fn next_change(
+    changes: &mut Option<WithdrawItemChanges>,
+) -> Option<mongodb::error::Result<(String, String)>> {
+    match changes {
+        Some(c) => c.next().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/**
+Same as `run_daemon`, but a withdraw item is re-checked as soon as it, or one
+of its validator signatures, changes in the db. A full sweep of the networks is
+still run every interval as a fallback, and if the change streams are not available.
+ **/
+pub async fn run_watcher(
+    processor: &dyn Processor,
+    networks: &[String],
+    interval: Duration,
+    jitter: Duration,
+    mut shutdown: Shutdown,
+) {
+    println!(
+        "Watching networks {:?} with a full sweep every {:?} (jitter up to {:?})",
+        networks, interval, jitter
+    );
+    let mut changes: Option<WithdrawItemChanges> = None;
+    let mut subscribe = true;
+    let mut next_sweep = Instant::now();
+    loop {
+        if changes.is_none() && subscribe {
+            subscribe = false;
+            match processor.watch_for_networks(networks).await {
+                Ok(c) => changes = Some(c),
+                Err(e) => println!("{}. Falling back to the periodic sweep", e.msg),
+            }
+        }
+        tokio::select! {
+            // Checked first, so that no new work is started once shutdown is requested
+            biased;
+            _ = shutdown.requested() => break,
+            _ = tokio::time::sleep_until(next_sweep) => {
+                if let Err(e) = processor.process_for_networks(networks).await {
+                    println!("{}", e.msg);
+                }
+                next_sweep = Instant::now() + next_wait(interval, jitter);
+                subscribe = true;
+            },
+            change = next_change(&mut changes) => match change {
+                Some(Ok((network, tx))) => {
+                    match processor.process_pending_item(&network, &tx).await {
+                        Ok(Some(ItemOutcome::Signed(wis))) =>
+                            println!("Signed {}:{} - {}", &network, &tx, &wis),
+                        Ok(_) => {},
+                        Err(e) => println!("Error processing {}:{} - {}", &network, &tx, e.msg),
+                    }
+                },
+                Some(Err(e)) => {
+                    println!("Error on the change stream: {}. Re-subscribing at the next sweep", e);
+                    changes = None;
+                },
+                None => {
+                    println!("Change stream closed. Re-subscribing at the next sweep");
+                    changes = None;
+                },
+            },
+        }
+    }
+}
@@ -1,2 +1,6 @@
+pub mod blocklist;
+pub mod daemon;
+pub mod shutdown;
 pub mod swap_processor;
 pub mod validator;
+pub mod volume_cap;
@@ -1,76 +1,1160 @@
-use super::validator::Validator;
-use crate::database::mongo::Database;
+use super::blocklist::Blocklist;
+use super::shutdown::Shutdown;
+use super::validator::{MultiSigVerdict, Validator};
+use super::volume_cap::{CapCheck, VolumeCaps};
+use crate::chain::evm_verifier::{EvmVerifier, SwapVerdict};
+use crate::database::mongo::{Database, WithdrawItemChanges};
+use crate::types::address::Address;
 use crate::types::errors::{BError, BResult};
-use crate::types::types::{WithdrawItem, WithdrawItemSignature};
+use crate::types::types::{
+    ContractConfig, ProcessorConfig, RetryConfig, SignedSwap, ValidationReport, WithdrawItem,
+    WithdrawItemSignature};
 use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use crate::crypto::crypto_utils::rand_hex;
+use crate::crypto::eip712::withdraw_signed_hash;
+use crate::types::utils::now;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-#[async_trait(?Send)]
-pub trait Processor {
-    async fn process_for_network(&self, network: &String) -> BResult<()>;
-    async fn process_withdraw_item(&self, wi: &WithdrawItem) -> BResult<()>;
+pub enum ItemOutcome {
+    Signed(WithdrawItemSignature),
+    BelowThreshold,
+    Invalid,
+    /// Not processed because the node is shutting down
+    Cancelled,
+    /// Being processed by another node
+    Leased,
+    /// Not signed because a volume cap is reached
+    CapReached,
+    /// Signed by this node before, or by enough super signers
+    AlreadySigned,
+    /// The transaction or the recipient is on the blocklist
+    Blocked,
+    /// The swap is not found or not confirmed on its source chain yet
+    SwapPending,
+}
+
+impl ItemOutcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ItemOutcome::Signed(_) => "signed",
+            ItemOutcome::BelowThreshold => "belowThreshold",
+            ItemOutcome::Invalid => "invalid",
+            ItemOutcome::Cancelled => "cancelled",
+            ItemOutcome::Leased => "leased",
+            ItemOutcome::CapReached => "capReached",
+            ItemOutcome::AlreadySigned => "alreadySigned",
+            ItemOutcome::Blocked => "blocked",
+            ItemOutcome::SwapPending => "swapPending",
+        }
+    }
+}
+
+/**
+Outcome of a processing round, by receive transaction id.
+ **/
+#[derive(Default)]
+pub struct RunSummary {
+    pub network: String,
+    pub signed: Vec<String>,
+    pub skipped: Vec<String>,
+    pub invalid: Vec<String>,
+    pub cancelled: Vec<String>,
+    pub leased: Vec<String>,
+    pub capped: Vec<String>,
+    pub already_signed: Vec<String>,
+    pub blocked: Vec<String>,
+    pub swap_pending: Vec<String>,
+    pub errored: Vec<(String, String)>,
+}
+
+impl RunSummary {
+    pub fn add(&mut self, transaction_id: &str, res: BResult<ItemOutcome>) {
+        let tx = String::from(transaction_id);
+        match res {
+            Ok(ItemOutcome::Signed(_)) => self.signed.push(tx),
+            Ok(ItemOutcome::BelowThreshold) => self.skipped.push(tx),
+            Ok(ItemOutcome::Invalid) => self.invalid.push(tx),
+            Ok(ItemOutcome::Cancelled) => self.cancelled.push(tx),
+            Ok(ItemOutcome::Leased) => self.leased.push(tx),
+            Ok(ItemOutcome::CapReached) => self.capped.push(tx),
+            Ok(ItemOutcome::AlreadySigned) => self.already_signed.push(tx),
+            Ok(ItemOutcome::Blocked) => self.blocked.push(tx),
+            Ok(ItemOutcome::SwapPending) => self.swap_pending.push(tx),
+            Err(e) => self.errored.push((tx, e.msg)),
+        }
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Summary for network {}:", &self.network)?;
+        writeln!(f, "  signed: {} {:?}", self.signed.len(), &self.signed)?;
+        writeln!(f, "  skipped (below threshold): {} {:?}", self.skipped.len(), &self.skipped)?;
+        writeln!(f, "  invalid: {} {:?}", self.invalid.len(), &self.invalid)?;
+        writeln!(f, "  cancelled: {} {:?}", self.cancelled.len(), &self.cancelled)?;
+        writeln!(f, "  leased by another node: {} {:?}", self.leased.len(), &self.leased)?;
+        writeln!(f, "  volume cap reached: {} {:?}", self.capped.len(), &self.capped)?;
+        writeln!(f, "  already signed: {} {:?}", self.already_signed.len(), &self.already_signed)?;
+        writeln!(f, "  blocked: {} {:?}", self.blocked.len(), &self.blocked)?;
+        writeln!(f, "  swap not confirmed yet: {} {:?}", self.swap_pending.len(), &self.swap_pending)?;
+        write!(f, "  errored: {}", self.errored.len())?;
+        for (tx, msg) in &self.errored {
+            write!(f, "\n    {} - {}", tx, msg)?;
+        }
+        Ok(())
+    }
+}
+
+/**
+Makes sure the hash to sign is the hash of the item's content, so that the
+hash cannot be swapped in the db.
+ **/
+fn verify_withdraw_hash(wi: &WithdrawItem) -> BResult<()> {
+    let expected = withdraw_signed_hash(&wi.pay_by_sig)?;
+    if !expected.eq_ignore_ascii_case(&wi.pay_by_sig.hash) {
+        return Err(BError::new(&format!(
+            "Withdraw hash mismatch. Expected {} but the item has {}",
+            &expected, &wi.pay_by_sig.hash)));
+    }
+    Ok(())
+}
+
+fn report_key(network: &str, transaction_id: &str) -> String {
+    format!("{}:{}", network, transaction_id)
+}
+
+/// Number of distinct super signers that signed the item
+fn super_signatures(super_signers: &[Address], wi: &WithdrawItem) -> usize {
+    wi.pay_by_sig
+        .signatures
+        .iter()
+        .filter_map(|s| Address::parse(&s.creator).ok())
+        .filter(|a| super_signers.is_empty() || super_signers.contains(a))
+        .collect::<HashSet<Address>>()
+        .len()
+}
+
+/// Why the item is blocked, if its transactions or recipient are on the blocklist
+fn blocked_reason(blocklist: &Blocklist, wi: &WithdrawItem) -> Option<String> {
+    if blocklist.is_transaction_blocked(&wi.receive_transaction_id) {
+        return Some(format!("Transaction {} is blocked", &wi.receive_transaction_id));
+    }
+    if blocklist.is_transaction_blocked(&wi.pay_by_sig.swap_tx_id) {
+        return Some(format!("Swap transaction {} is blocked", &wi.pay_by_sig.swap_tx_id));
+    }
+    if blocklist.is_recipient_blocked(&wi.pay_by_sig.payee) {
+        return Some(format!("Recipient {} is blocked", &wi.pay_by_sig.payee));
+    }
+    None
+}
+
+/**
+Makes sure the item points at a known bridge contract on its network.
+ **/
+fn verify_contract(contracts: &HashMap<String, Vec<ContractConfig>>, wi: &WithdrawItem)
+    -> BResult<()> {
+    if contracts.is_empty() {
+        return Ok(());
+    }
+    let pbs = &wi.pay_by_sig;
+    let known = contracts
+        .get(&wi.receive_network)
+        .is_some_and(|cs| cs.iter().any(|c| c.matches(pbs)));
+    if !known {
+        return Err(BError::new(&format!(
+            "Unknown contract {} ({} v{}) on chain {}",
+            &pbs.contract_address, &pbs.contract_name, &pbs.contract_version,
+            pbs.source_chain_id)));
+    }
+    Ok(())
+}
+
+#[async_trait]
+pub trait Processor: Send + Sync {
+    async fn process_for_network(&self, network: &String) -> BResult<RunSummary>;
+    async fn process_for_networks(&self, networks: &[String]) -> BResult<()>;
+    async fn process_transaction(&self, network: &str, transaction_id: &str) -> BResult<()>;
+    async fn process_pending_item(&self, network: &str, transaction_id: &str)
+        -> BResult<Option<ItemOutcome>>;
+    async fn watch_for_networks(&self, networks: &[String]) -> BResult<WithdrawItemChanges>;
+    async fn process_withdraw_item(&self, wi: &WithdrawItem) -> BResult<ItemOutcome>;
+    fn wipe_key(&self);
 }
 
 pub struct SwapProcessor<V: Validator, D: Database> {
+    config: ProcessorConfig,
     validator: Box<V>,
     db: Box<D>,
+    shutdown: Shutdown,
+    lease_owner: String,
+    caps: VolumeCaps,
+    verifier: Option<EvmVerifier>,
+    blocklist: Arc<Blocklist>,
+    /// (outcome, reason) of the last report saved per item, to skip unchanged ones
+    saved_reports: Mutex<HashMap<String, (String, String)>>,
 }
 
 impl<V: Validator, D: Database> SwapProcessor<V, D> {
-    pub fn new(validator: V, db: D) -> Self {
+    pub fn new(config: &ProcessorConfig, retry: &RetryConfig, validator: V, db: D,
+               blocklist: Arc<Blocklist>, shutdown: Shutdown) -> Self {
+        let lease_owner = config
+            .lease
+            .as_ref()
+            .and_then(|l| l.owner.clone())
+            .unwrap_or_else(|| rand_hex(8));
         SwapProcessor {
+            config: config.clone(),
             validator: Box::new(validator),
             db: Box::new(db),
+            shutdown,
+            lease_owner,
+            caps: VolumeCaps::new(&config.caps, config.dry_run),
+            verifier: match config.chains.is_empty() {
+                true => None,
+                false => Some(EvmVerifier::new(&config.chains, retry)),
+            },
+            blocklist,
+            saved_reports: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Signed by this node, or by enough super signers
+    fn already_signed(&self, wi: &WithdrawItem) -> bool {
+        self.validator.has_signed(wi)
+            || super_signatures(&self.config.super_signers, wi) >= self.config.super_threshold as usize
+    }
+
+    /// Renews the lease every third of its ttl. Only returns if the lease is lost
+    async fn renew_lease(&self, key: &str, ttl_ms: i64) -> BError {
+        loop {
+            tokio::time::sleep(Duration::from_millis((ttl_ms / 3) as u64)).await;
+            match self.db.acquire_lease(key, &self.lease_owner, ttl_ms).await {
+                Ok(true) => {},
+                Ok(false) => return BError::new(&format!("Lost the lease on {}", key)),
+                Err(e) => return BError::new(&format!("Error renewing the lease on {} - {}", key, e)),
+            }
+        }
+    }
+
+    /**
+    Processes the item and saves a validation report with the outcome. Nothing is
+    saved in dry run, or if the outcome and reason did not change since the last
+    report of this node. Items signed before only get a report if this node saved
+    another one for them.
+     **/
+    async fn sign_withdraw_item(&self, wi: &WithdrawItem) -> BResult<ItemOutcome> {
+        let mut report = ValidationReport {
+            network: wi.receive_network.clone(),
+            transaction_id: wi.receive_transaction_id.clone(),
+            node: self.lease_owner.clone(),
+            ..Default::default()
+        };
+        let res = self.check_and_sign(wi, &mut report).await;
+        match &res {
+            Ok(ItemOutcome::AlreadySigned) if !self.saved_reports.lock().unwrap()
+                .contains_key(&report_key(&report.network, &report.transaction_id)) => return res,
+            Ok(outcome) => report.outcome = String::from(outcome.name()),
+            Err(e) => {
+                report.outcome = String::from("error");
+                report.reason = e.msg.clone();
+            },
+        }
+        if !self.config.dry_run {
+            self.save_report_if_changed(&mut report).await;
+        }
+        res
+    }
+
+    async fn save_report_if_changed(&self, report: &mut ValidationReport) {
+        let key = report_key(&report.network, &report.transaction_id);
+        let last = (report.outcome.clone(), report.reason.clone());
+        if self.saved_reports.lock().unwrap().get(&key) == Some(&last) {
+            return;
+        }
+        report.creation_time = now();
+        if let Err(e) = self.db.save_validation_report(report).await {
+            println!("Error saving the validation report for {} - {}", &key, e);
+            return;
         }
+        let mut saved = self.saved_reports.lock().unwrap();
+        match report.outcome.as_str() {
+            // Not pending any more
+            "signed" | "alreadySigned" => saved.remove(&key),
+            _ => saved.insert(key, last),
+        };
+    }
+
+    async fn check_and_sign(&self, wi: &WithdrawItem, report: &mut ValidationReport)
+        -> BResult<ItemOutcome> {
+        println!("Processing wi: {}:{}", &wi.receive_network, &wi.receive_transaction_id);
+        if self.already_signed(wi) {
+            return Ok(ItemOutcome::AlreadySigned);
+        }
+        if let Some(reason) = blocked_reason(&self.blocklist, wi) {
+            println!("BLOCKED: refusing to sign {}:{} - {}",
+                     &wi.receive_network, &wi.receive_transaction_id, &reason);
+            report.reason = reason;
+            return Ok(ItemOutcome::Blocked);
+        }
+        if let Err(e) = verify_withdraw_hash(wi) {
+            println!("Refusing to sign {}:{} - {}",
+                     &wi.receive_network, &wi.receive_transaction_id, e.msg);
+            report.reason = e.msg;
+            return Ok(ItemOutcome::Invalid);
+        }
+        if let Err(e) = verify_contract(&self.config.contracts, wi) {
+            println!("FLAGGED: refusing to sign {}:{} - {}",
+                     &wi.receive_network, &wi.receive_transaction_id, e.msg);
+            report.reason = e.msg;
+            return Ok(ItemOutcome::Invalid);
+        }
+        let sigs = self
+            .db
+            .signed_swaps(&wi.receive_network, &wi.receive_transaction_id)
+            .await
+            .map_err(|_| BError::new("Cannot get signed swaps"))?;
+        println!("We have {} signatures", sigs.len());
+        let check = self.validator.validate_multi_sig(wi, &sigs);
+        report.reason = check.reason;
+        report.signatures = check.signatures;
+        report.valid_weight = check.valid_weight;
+        report.required_weight = check.required_weight;
+        report.rejected = check.rejected;
+        match check.verdict {
+            MultiSigVerdict::Valid => {},
+            MultiSigVerdict::BelowThreshold => return Ok(ItemOutcome::BelowThreshold),
+            MultiSigVerdict::Invalid => return Ok(ItemOutcome::Invalid),
+        }
+        // Only once the item could be signed, as it takes several RPC calls
+        if let Some(verifier) = &self.verifier {
+            match verifier.verify(wi).await? {
+                SwapVerdict::Confirmed => {},
+                SwapVerdict::Pending(reason) => {
+                    println!("Not signing {}:{} yet - {}",
+                             &wi.receive_network, &wi.receive_transaction_id, &reason);
+                    report.reason = reason;
+                    return Ok(ItemOutcome::SwapPending);
+                },
+                SwapVerdict::Rejected(reason) => {
+                    println!("FLAGGED: refusing to sign {}:{} - {}",
+                             &wi.receive_network, &wi.receive_transaction_id, &reason);
+                    report.reason = reason;
+                    return Ok(ItemOutcome::Invalid);
+                },
+            }
+        }
+        let cap_check = self.caps.reserve(
+            self.db.as_ref(),
+            &wi.receive_network,
+            &wi.receive_transaction_id,
+            &wi.pay_by_sig.token,
+            &wi.pay_by_sig.amount,
+            now()).await?;
+        let reservation = match cap_check {
+            CapCheck::Reserved(r) => r,
+            CapCheck::Reached(reason) => {
+                println!("Not signing {}:{} - {}",
+                         &wi.receive_network, &wi.receive_transaction_id, &reason);
+                report.reason = reason;
+                return Ok(ItemOutcome::CapReached);
+            }
+        };
+        let res = self.add_our_signature(wi, &sigs).await;
+        self.caps.release(&reservation);
+        res
+    }
+
+    async fn add_our_signature(&self, wi: &WithdrawItem, sigs: &[SignedSwap])
+        -> BResult<ItemOutcome> {
+        let final_sig = self
+            .validator
+            .produce_our_signature(wi, sigs, self.config.dry_run)
+            .await
+            .map_err(|e| BError::new(&format!("Error producing the signature: {}", e.msg)))?;
+        let wis = WithdrawItemSignature {
+            signature: final_sig.signature.clone(),
+            creation_time: final_sig.creation_time,
+            creator: final_sig.signer,
+        };
+        println!("Produced sig {}", &wis);
+        if self.config.dry_run {
+            println!("Dry run. Not adding the signature to {}:{}",
+                     &wi.receive_network, &wi.receive_transaction_id);
+            return Ok(ItemOutcome::Signed(wis));
+        }
+        self.db
+            .add_signature_to_withdraw_item(
+                &wi.receive_network,
+                &wi.receive_transaction_id,
+                wi.v,
+                &wis,
+            )
+            .await
+            .map_err(|_| BError::new("Error adding signature to withdraw item"))?
+            .ok_or_else(|| BError::new(
+                "Withdraw item was updated by another process. Signature not added"))?;
+        Ok(ItemOutcome::Signed(wis))
     }
 }
 
-#[async_trait(?Send)]
+#[async_trait]
 impl<V: Validator, D: Database> Processor for SwapProcessor<V, D> {
-    async fn process_for_network(&self, network: &String) -> BResult<()> {
-        let withdraw_items = self
+    /**
+    Process all the pending items for the network. A failing item is recorded
+    in the summary and does not stop the others.
+     **/
+    async fn process_for_network(&self, network: &String) -> BResult<RunSummary> {
+        let pending = self
             .db
-            .pending_withdraw_items(network)
+            .pending_withdraw_items(network, self.config.super_threshold)
             .await
             .map_err(|_| BError::new("Error getting withdraw items"))?;
-        println!("Loaded {} withdraw items for network {}", &withdraw_items.len(), network);
-        for wi in &withdraw_items {
-            self.process_withdraw_item(wi).await?
+        println!("Loaded {} withdraw items for network {}", &pending.items.len(), network);
+        // Items that left the queue will not be processed again
+        let pending_keys: HashSet<String> = pending
+            .items
+            .iter()
+            .map(|wi| report_key(&wi.receive_network, &wi.receive_transaction_id))
+            .collect();
+        self.saved_reports
+            .lock()
+            .unwrap()
+            .retain(|k, _| !k.starts_with(&format!("{}:", network)) || pending_keys.contains(k));
+        let parallelism = self.config.parallelism.max(1);
+        let jobs: Vec<_> = pending
+            .items
+            .iter()
+            .map(|wi| async move {
+                // Stop taking new items once shutdown is requested
+                if self.shutdown.is_requested() {
+                    return (&wi.receive_transaction_id, Ok(ItemOutcome::Cancelled));
+                }
+                (&wi.receive_transaction_id, self.process_withdraw_item(wi).await)
+            })
+            .collect();
+        let results: Vec<(&String, BResult<ItemOutcome>)> = stream::iter(jobs)
+            .buffer_unordered(parallelism)
+            .collect()
+            .await;
+        let mut summary = RunSummary {
+            network: network.clone(),
+            errored: pending.malformed,
+            ..Default::default()
+        };
+        for (tx, r) in results {
+            summary.add(tx, r);
+        }
+        println!("{}", &summary);
+        Ok(summary)
+    }
+
+    /**
+    Process the networks one after the other, sharing the key and the db client.
+    A failing network does not stop the others.
+     **/
+    async fn process_for_networks(&self, networks: &[String]) -> BResult<()> {
+        let mut failed: Vec<&String> = Vec::new();
+        for network in networks {
+            if self.shutdown.is_requested() {
+                break;
+            }
+            if let Err(e) = self.process_for_network(network).await {
+                println!("Error processing for the network: {} - {}", network, e.msg);
+                failed.push(network);
+            }
+        }
+        if !failed.is_empty() {
+            return Err(BError::new(&format!(
+                "Error processing for networks: {:?}", failed)));
         }
         Ok(())
     }
 
-    async fn process_withdraw_item(&self, wi: &WithdrawItem) -> BResult<()> {
-        println!("Processing wi: {}:{}", &wi.receive_network, &wi.receive_transaction_id);
+    /**
+    Process a single withdraw item and print a detailed verdict. Used to investigate
+    one withdrawal without touching the rest of the queue.
+     **/
+    async fn process_transaction(&self, network: &str, transaction_id: &str) -> BResult<()> {
+        let wi = self
+            .db
+            .withdraw_item(network, transaction_id)
+            .await
+            .map_err(|_| BError::new("Error getting the withdraw item"))?
+            .ok_or_else(|| BError::new(&format!(
+                "No withdraw item found for {}:{}", network, transaction_id)))?;
+        println!("Withdraw item: {}", &wi);
+        for s in &wi.pay_by_sig.signatures {
+            println!("  Super signature: {}", s);
+        }
+        match withdraw_signed_hash(&wi.pay_by_sig) {
+            Ok(h) => println!("Computed hash: {}, matches: {}",
+                              &h, h.eq_ignore_ascii_case(&wi.pay_by_sig.hash)),
+            Err(e) => println!("Cannot compute the hash: {}", e.msg),
+        }
+        if self.already_signed(&wi) {
+            println!("Verdict: already signed by us or by enough super signers. Nothing to do");
+            return Ok(());
+        }
         let sigs = self
             .db
             .signed_swaps(&wi.receive_network, &wi.receive_transaction_id)
             .await
             .map_err(|_| BError::new("Cannot get signed swaps"))?;
-        println!("We have {} signatures", sigs.len());
-        if self
-            .validator
-            .is_multi_sig_valid(&wi.pay_by_sig.hash, &sigs)
-        {
-            let final_sig = self
-                .validator
-                .produce_our_signature(&wi.pay_by_sig.hash, &sigs)
-                .map_err(|_| BError::new("Error producing the signature"))?;
-            let wis = WithdrawItemSignature {
-                signature: final_sig.signature.clone(),
-                creation_time: final_sig.creation_time,
-                creator: final_sig.signer,
-            };
-            println!("Produced sig {}", &wis);
-            self.db
-                .add_signature_to_withdraw_item(
-                    &wi.receive_network,
-                    &wi.receive_transaction_id,
-                    wi.v,
-                    &wis,
-                )
-                .await
-                .map_err(|_| BError::new("Error adding signature to withdraw item"))?;
+        println!("Validator signatures: {}", sigs.len());
+        for s in &sigs {
+            let matches_hash = wi.pay_by_sig.hash.eq(&s.msg_hash);
+            let verified = self.validator.verify_sig(&wi.receive_network, &s.msg_hash, s);
+            println!(
+                "  {} - matches hash: {}, verified: {}",
+                s, matches_hash, verified
+            );
+        }
+        match self.process_withdraw_item(&wi).await? {
+            ItemOutcome::Signed(wis) if self.config.dry_run =>
+                println!("Verdict: would sign (dry run). {}", &wis),
+            ItemOutcome::Signed(wis) => println!("Verdict: signed. {}", &wis),
+            ItemOutcome::BelowThreshold =>
+                println!("Verdict: not signed. Not enough valid validator signatures yet"),
+            ItemOutcome::Invalid =>
+                println!("Verdict: not signed. The validator signatures are not valid"),
+            ItemOutcome::Cancelled => println!("Verdict: not processed. Shutting down"),
+            ItemOutcome::Leased => println!("Verdict: not processed. Leased by another node"),
+            ItemOutcome::CapReached => println!("Verdict: not signed. Volume cap reached"),
+            ItemOutcome::AlreadySigned =>
+                println!("Verdict: not signed. Already signed by us or by enough super signers"),
+            ItemOutcome::Blocked => println!("Verdict: not signed. Blocked"),
+            ItemOutcome::SwapPending =>
+                println!("Verdict: not signed. The swap is not confirmed on its source chain yet"),
         }
         Ok(())
     }
+
+    /**
+    Re-load the withdraw item and process it if it is still pending. Returns None if
+    there is nothing to do.
+     **/
+    async fn process_pending_item(&self, network: &str, transaction_id: &str)
+        -> BResult<Option<ItemOutcome>> {
+        let wi = self
+            .db
+            .withdraw_item(network, transaction_id)
+            .await
+            .map_err(|_| BError::new("Error getting the withdraw item"))?;
+        match wi {
+            Some(wi) if wi.signatures < self.config.super_threshold as i32 => Ok(Some(self.process_withdraw_item(&wi).await?)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn watch_for_networks(&self, networks: &[String]) -> BResult<WithdrawItemChanges> {
+        self.db
+            .watch_withdraw_items(networks)
+            .await
+            .map_err(|e| BError::new(&format!("Error watching withdraw items: {}", e)))
+    }
+
+    /**
+    With a lease configured, the item is only processed if this node holds its lease.
+    The lease is renewed while processing, and released at the end. Dry run does not
+    take leases, so it neither writes them nor keeps other nodes from the item.
+     **/
+    async fn process_withdraw_item(&self, wi: &WithdrawItem) -> BResult<ItemOutcome> {
+        let lease = match &self.config.lease {
+            Some(l) if !self.config.dry_run => l,
+            _ => return self.sign_withdraw_item(wi).await,
+        };
+        let key = format!("{}:{}", &wi.receive_network, &wi.receive_transaction_id);
+        let ttl_ms = (lease.ttl_secs * 1000) as i64;
+        let acquired = self
+            .db
+            .acquire_lease(&key, &self.lease_owner, ttl_ms)
+            .await
+            .map_err(|_| BError::new("Error acquiring the lease"))?;
+        if !acquired {
+            println!("{} is leased by another node. Skipping", &key);
+            return Ok(ItemOutcome::Leased);
+        }
+        let sign = self.sign_withdraw_item(wi);
+        tokio::pin!(sign);
+        let res = tokio::select! {
+            r = &mut sign => r,
+            e = self.renew_lease(&key, ttl_ms) => {
+                // Cancelling could leave the signature write half done
+                println!("{}. Finishing the item without renewing it", e.msg);
+                sign.await
+            },
+        };
+        if let Err(e) = self.db.release_lease(&key, &self.lease_owner).await {
+            println!("Error releasing the lease on {} - {}", &key, e);
+        }
+        res
+    }
+
+    fn wipe_key(&self) {
+        self.validator.wipe_key();
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::{ItemOutcome, Processor, SwapProcessor};
+    #[allow(unused_imports)]
+    use crate::crypto::eip712::withdraw_signed_hash;
+    #[allow(unused_imports)]
+    use crate::validator::shutdown::shutdown_channel;
+    #[allow(unused_imports)]
+    use crate::validator::blocklist::{BlockedEntries, Blocklist};
+    #[allow(unused_imports)]
+    use crate::database::mongo::{Database, PendingWithdrawItems, WithdrawItemChanges};
+    #[allow(unused_imports)]
+    use crate::types::address::Address;
+    #[allow(unused_imports)]
+    use crate::types::types::{ContractConfig, LeaseConfig, RetryConfig, ValidationReport, VolumeCapConfig, PayBySig, ProcessorConfig, SignedSwap, WithdrawItem, WithdrawItemSignature};
+    #[allow(unused_imports)]
+    use crate::validator::validator::{MultiSigCheck, MultiSigVerdict, Validator, ValidatorError};
+    #[allow(unused_imports)]
+    use async_trait::async_trait;
+    #[allow(unused_imports)]
+    use mongodb::bson::Document;
+    #[allow(unused_imports)]
+    use crate::types::utils::now;
+    #[allow(unused_imports)]
+    use std::collections::HashSet;
+    #[allow(unused_imports)]
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    #[allow(unused_imports)]
+    use std::sync::{Arc, Mutex};
+    #[allow(unused_imports)]
+    use std::time::Duration;
+
+    #[allow(dead_code)]
+    fn withdraw_item(tx: &str) -> WithdrawItem {
+        let mut pay_by_sig = PayBySig {
+            token: String::from("0x0000000000000000000000000000000000000002"),
+            payee: String::from("0x0000000000000000000000000000000000000003"),
+            amount: String::from("1000000000000000000"),
+            source_chain_id: 4,
+            swap_tx_id: String::from(tx),
+            contract_name: String::from("FERRUM_TOKEN_BRIDGE_POOL"),
+            contract_version: String::from("000.003"),
+            contract_address: String::from("0x0000000000000000000000000000000000000001"),
+            hash: String::new(),
+            signatures: vec![],
+        };
+        pay_by_sig.hash = withdraw_signed_hash(&pay_by_sig).ok().unwrap();
+        WithdrawItem {
+            v: 0,
+            version: String::from("1.0"),
+            receive_network: String::from("RINKEBY"),
+            receive_transaction_id: String::from(tx),
+            send_network: String::from("BSC_TESTNET"),
+            pay_by_sig,
+            signatures: 0,
+        }
+    }
+
+    #[allow(dead_code)]
+    #[derive(Default)]
+    struct MockDb {
+        items: usize,
+        /// Transaction ids of items that cannot be parsed
+        malformed: Vec<String>,
+        fail_tx: Option<String>,
+        foreign_lease: Option<String>,
+        /// Leases cannot be renewed once taken
+        lose_leases: bool,
+        lease_calls: AtomicUsize,
+        /// Time taken by each signature write
+        write_delay_ms: u64,
+        released: AtomicUsize,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+        added: AtomicUsize,
+        reports: Mutex<Vec<ValidationReport>>,
+        /// (time, transaction id, amount) of the items with a super signature
+        signed: Mutex<Vec<(i64, String, String)>>,
+        reached_caps: Mutex<HashSet<String>>,
+        /// Super signers already on the items returned by `withdraw_item`
+        super_signed_by: Vec<String>,
+    }
+
+    #[async_trait]
+    impl Database for MockDb {
+        async fn add_signature_to_withdraw_item(
+            &self,
+            _network: &String,
+            transaction_id: &String,
+            _v: i32,
+            _wis: &WithdrawItemSignature,
+        ) -> mongodb::error::Result<Option<Document>> {
+            tokio::time::sleep(Duration::from_millis(self.write_delay_ms)).await;
+            self.added.fetch_add(1, Ordering::SeqCst);
+            let amount = withdraw_item(transaction_id).pay_by_sig.amount;
+            self.signed.lock().unwrap().push((now(), transaction_id.clone(), amount));
+            Ok(Some(Document::new()))
+        }
+
+        async fn signed_swaps(
+            &self,
+            network: &String,
+            transaction_id: &String,
+        ) -> mongodb::error::Result<Vec<SignedSwap>> {
+            if self.fail_tx.as_ref() == Some(transaction_id) {
+                return Err(std::io::Error::other("Boom").into());
+            }
+            let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(vec![SignedSwap {
+                creation_time: 0,
+                network: network.clone(),
+                transaction_id: transaction_id.clone(),
+                msg_hash: String::from("0x01"),
+                signer: String::from("0x02"),
+                signature: String::from("0x03"),
+            }])
+        }
+
+        async fn pending_withdraw_items(
+            &self,
+            _network: &String,
+            _super_threshold: u32,
+        ) -> mongodb::error::Result<PendingWithdrawItems> {
+            Ok(PendingWithdrawItems {
+                items: (0..self.items).map(|i| withdraw_item(&format!("0x{}", i))).collect(),
+                malformed: self
+                    .malformed
+                    .iter()
+                    .map(|tx| (tx.clone(), String::from("Malformed withdraw item")))
+                    .collect(),
+            })
+        }
+
+        async fn withdraw_item(
+            &self,
+            _network: &str,
+            transaction_id: &str,
+        ) -> mongodb::error::Result<Option<WithdrawItem>> {
+            let mut wi = withdraw_item(transaction_id);
+            for creator in &self.super_signed_by {
+                wi.pay_by_sig.signatures.push(WithdrawItemSignature {
+                    creation_time: 0,
+                    creator: creator.clone(),
+                    signature: String::from("0x05"),
+                });
+            }
+            wi.signatures = self.super_signed_by.len() as i32;
+            Ok(Some(wi))
+        }
+
+        async fn watch_withdraw_items(
+            &self,
+            _networks: &[String],
+        ) -> mongodb::error::Result<WithdrawItemChanges> {
+            Ok(Box::pin(futures::stream::empty()))
+        }
+
+        async fn acquire_lease(
+            &self,
+            key: &str,
+            _owner: &str,
+            _ttl_ms: i64,
+        ) -> mongodb::error::Result<bool> {
+            if self.lose_leases && self.lease_calls.fetch_add(1, Ordering::SeqCst) > 0 {
+                return Ok(false);
+            }
+            Ok(self.foreign_lease.as_ref().map_or(true, |l| !key.ends_with(l.as_str())))
+        }
+
+        async fn release_lease(&self, _key: &str, _owner: &str) -> mongodb::error::Result<()> {
+            self.released.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn save_validation_report(&self, report: &ValidationReport) -> mongodb::error::Result<()> {
+            self.reports.lock().unwrap().push(report.clone());
+            Ok(())
+        }
+
+        async fn blocklist_entries(&self) -> mongodb::error::Result<Vec<(String, String)>> {
+            Ok(vec![])
+        }
+
+        async fn signed_amounts(&self, _network: &str, _token: &Address, since: i64)
+            -> mongodb::error::Result<Vec<(String, String)>> {
+            Ok(self.signed.lock().unwrap().iter()
+                .filter(|(t, _, _)| *t > since)
+                .map(|(_, tx, a)| (tx.clone(), a.clone()))
+                .collect())
+        }
+
+        async fn is_volume_cap_reached(&self, key: &str) -> mongodb::error::Result<bool> {
+            Ok(self.reached_caps.lock().unwrap().contains(key))
+        }
+
+        async fn set_volume_cap_reached(&self, key: &str, _reason: &str) -> mongodb::error::Result<()> {
+            self.reached_caps.lock().unwrap().insert(String::from(key));
+            Ok(())
+        }
+    }
+
+    #[allow(dead_code)]
+    const MOCK_SIGNER: &str = "0x0000000000000000000000000000000000000004";
+
+    #[allow(dead_code)]
+    struct MockValidator {}
+
+    #[async_trait]
+    impl Validator for MockValidator {
+        fn validate_multi_sig(&self, _wi: &WithdrawItem, all_signatures: &[SignedSwap])
+            -> MultiSigCheck {
+            let verdict = match all_signatures.len() {
+                0 => MultiSigVerdict::BelowThreshold,
+                _ => MultiSigVerdict::Valid,
+            };
+            MultiSigCheck {
+                verdict,
+                reason: String::new(),
+                signatures: all_signatures.len(),
+                valid_weight: all_signatures.len() as u64,
+                required_weight: 1,
+                rejected: vec![],
+            }
+        }
+
+        fn check_sig(&self, _network: &str, _msg: &str, _s: &SignedSwap) -> Result<(), String> {
+            Ok(())
+        }
+
+        async fn produce_our_signature(
+            &self,
+            wi: &WithdrawItem,
+            _signatures: &[SignedSwap],
+            _dry_run: bool,
+        ) -> Result<SignedSwap, ValidatorError> {
+            Ok(SignedSwap {
+                creation_time: 0,
+                network: wi.receive_network.clone(),
+                transaction_id: wi.receive_transaction_id.clone(),
+                msg_hash: wi.pay_by_sig.hash.clone(),
+                signer: String::from(MOCK_SIGNER),
+                signature: String::from("0x05"),
+            })
+        }
+
+        fn has_signed(&self, wi: &WithdrawItem) -> bool {
+            wi.pay_by_sig.signatures.iter().any(|s| s.creator.eq(MOCK_SIGNER))
+        }
+
+        fn wipe_key(&self) {}
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_process_for_network_is_bounded_and_concurrent() {
+        let db = MockDb { items: 10, ..Default::default() };
+        let p = Arc::new(SwapProcessor::new(
+            &ProcessorConfig { parallelism: 3, ..Default::default() }, &RetryConfig::default(), MockValidator {}, db,
+            Arc::default(), shutdown_channel().1));
+        let spawned = p.clone();
+        tokio::spawn(async move {
+            spawned.process_for_network(&String::from("RINKEBY")).await
+        }).await.unwrap().ok().expect("Processing failed");
+        assert_eq!(p.db.added.load(Ordering::SeqCst), 10, "All items must be signed");
+        assert_eq!(p.db.max_in_flight.load(Ordering::SeqCst), 3, "Parallelism not respected");
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_does_not_write() {
+        let db = MockDb { items: 4, ..Default::default() };
+        let p = SwapProcessor::new(
+            &ProcessorConfig { parallelism: 2, dry_run: true, ..Default::default() }, &RetryConfig::default(), MockValidator {}, db,
+            Arc::default(), shutdown_channel().1);
+        p.process_for_network(&String::from("RINKEBY")).await.ok().expect("Processing failed");
+        let wis = p.process_withdraw_item(&withdraw_item("0x1")).await.ok().expect("Processing failed");
+        assert!(matches!(wis, ItemOutcome::Signed(_)), "Signature must be produced in dry run");
+        assert_eq!(p.db.added.load(Ordering::SeqCst), 0, "Dry run must not write");
+        assert!(p.db.reports.lock().unwrap().is_empty(), "Dry run must not write reports");
+    }
+
+    #[tokio::test]
+    async fn test_failing_item_does_not_stop_the_run() {
+        let db = MockDb { items: 5, fail_tx: Some(String::from("0x2")), ..Default::default() };
+        let p = SwapProcessor::new(
+            &ProcessorConfig::default(), &RetryConfig::default(), MockValidator {}, db, Arc::default(),
+            shutdown_channel().1);
+        let summary = p.process_for_network(&String::from("RINKEBY")).await
+            .ok().expect("Processing failed");
+        assert_eq!(summary.signed.len(), 4, "Other items must be signed");
+        assert_eq!(summary.errored.len(), 1);
+        assert_eq!(summary.errored[0].0, "0x2");
+        assert_eq!(p.db.added.load(Ordering::SeqCst), 4);
+
+        let db = MockDb { items: 2, malformed: vec![String::from("0x9")], ..Default::default() };
+        let p = SwapProcessor::new(
+            &ProcessorConfig::default(), &RetryConfig::default(), MockValidator {}, db, Arc::default(),
+            shutdown_channel().1);
+        let summary = p.process_for_network(&String::from("RINKEBY")).await
+            .ok().expect("Processing failed");
+        assert_eq!(summary.signed.len(), 2);
+        assert_eq!(summary.errored.len(), 1, "Malformed items must be reported");
+        assert_eq!(summary.errored[0].0, "0x9");
+    }
+
+    #[tokio::test]
+    async fn test_no_new_items_after_shutdown() {
+        let db = MockDb { items: 3, ..Default::default() };
+        let (trigger, shutdown) = shutdown_channel();
+        let p = SwapProcessor::new(&ProcessorConfig::default(), &RetryConfig::default(), MockValidator {}, db, Arc::default(),
+            shutdown);
+        trigger.trigger();
+        let summary = p.process_for_network(&String::from("RINKEBY")).await
+            .ok().expect("Processing failed");
+        assert_eq!(summary.cancelled.len(), 3);
+        assert_eq!(p.db.added.load(Ordering::SeqCst), 0, "Nothing must be written");
+    }
+
+    #[tokio::test]
+    async fn test_items_leased_by_another_node_are_skipped() {
+        let db = MockDb { items: 3, foreign_lease: Some(String::from("0x1")), ..Default::default() };
+        let config = ProcessorConfig { lease: Some(LeaseConfig::default()), ..Default::default() };
+        let p = SwapProcessor::new(&config, &RetryConfig::default(), MockValidator {}, db, Arc::default(),
+            shutdown_channel().1);
+        let summary = p.process_for_network(&String::from("RINKEBY")).await
+            .ok().expect("Processing failed");
+        assert_eq!(summary.leased, vec![String::from("0x1")]);
+        assert_eq!(summary.signed.len(), 2);
+        assert_eq!(p.db.released.load(Ordering::SeqCst), 2, "Acquired leases must be released");
+
+        // Dry run neither takes the leases nor is kept from the items
+        let db = MockDb { items: 3, foreign_lease: Some(String::from("0x1")), ..Default::default() };
+        let config = ProcessorConfig { dry_run: true, ..config };
+        let p = SwapProcessor::new(&config, &RetryConfig::default(), MockValidator {}, db, Arc::default(),
+            shutdown_channel().1);
+        let summary = p.process_for_network(&String::from("RINKEBY")).await
+            .ok().expect("Processing failed");
+        assert_eq!(summary.signed.len(), 3);
+        assert_eq!(p.db.released.load(Ordering::SeqCst), 0, "Dry run must not take leases");
+
+        // A lease lost while writing does not cancel the write
+        let db = MockDb { items: 1, lose_leases: true, write_delay_ms: 600, ..Default::default() };
+        let config = ProcessorConfig {
+            lease: Some(LeaseConfig { ttl_secs: 1, ..Default::default() }),
+            ..Default::default()
+        };
+        let p = SwapProcessor::new(&config, &RetryConfig::default(), MockValidator {}, db, Arc::default(),
+            shutdown_channel().1);
+        let summary = p.process_for_network(&String::from("RINKEBY")).await
+            .ok().expect("Processing failed");
+        assert_eq!(summary.signed.len(), 1);
+        assert_eq!(p.db.added.load(Ordering::SeqCst), 1);
+        assert!(p.db.lease_calls.load(Ordering::SeqCst) > 1, "The renewal must have failed");
+
+        assert!(serde_json::from_str::<LeaseConfig>(r#"{"ttlSecs": 0}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tampered_hash_is_not_signed() {
+        let db = MockDb::default();
+        let p = SwapProcessor::new(
+            &ProcessorConfig::default(), &RetryConfig::default(), MockValidator {}, db, Arc::default(),
+            shutdown_channel().1);
+        let mut wi = withdraw_item("0x1");
+        wi.pay_by_sig.payee = String::from("0x0000000000000000000000000000000000000004");
+        let res = p.process_withdraw_item(&wi).await.ok().expect("Processing failed");
+        assert!(matches!(res, ItemOutcome::Invalid), "Must refuse a hash that does not match");
+        assert_eq!(p.db.added.load(Ordering::SeqCst), 0);
+        // Unchanged reports are not saved again
+        p.process_withdraw_item(&wi).await.ok().expect("Processing failed");
+        let reports = p.db.reports.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].outcome, "invalid");
+        assert!(reports[0].reason.contains("hash mismatch"));
+    }
+
+    #[tokio::test]
+    async fn test_reports_of_items_signed_elsewhere_are_closed() {
+        let p = SwapProcessor::new(
+            &ProcessorConfig::default(), &RetryConfig::default(), MockValidator {}, MockDb::default(),
+            Arc::default(), shutdown_channel().1);
+        let mut wi = withdraw_item("0x1");
+        wi.pay_by_sig.payee = String::from("0x0000000000000000000000000000000000000004");
+        p.process_withdraw_item(&wi).await.ok().expect("Processing failed");
+        // Another node with our key signed it
+        wi.pay_by_sig.signatures.push(WithdrawItemSignature {
+            creation_time: 0,
+            creator: String::from(MOCK_SIGNER),
+            signature: String::from("0x05"),
+        });
+        for _ in 0..2 {
+            let res = p.process_withdraw_item(&wi).await.ok().expect("Processing failed");
+            assert!(matches!(res, ItemOutcome::AlreadySigned));
+        }
+        assert!(p.saved_reports.lock().unwrap().is_empty());
+        let outcomes: Vec<String> = p.db.reports.lock().unwrap().iter().map(|r| r.outcome.clone()).collect();
+        assert_eq!(outcomes, vec!["invalid", "alreadySigned"]);
+
+        // Items that left the queue are forgotten
+        p.saved_reports.lock().unwrap().insert(
+            String::from("RINKEBY:0x9"), (String::from("belowThreshold"), String::new()));
+        p.saved_reports.lock().unwrap().insert(
+            String::from("BSC:0x9"), (String::from("belowThreshold"), String::new()));
+        p.process_for_network(&String::from("RINKEBY")).await.ok().expect("Processing failed");
+        let keys: Vec<String> = p.saved_reports.lock().unwrap().keys().cloned().collect();
+        assert_eq!(keys, vec![String::from("BSC:0x9")]);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_contract_is_not_signed() {
+        let contract = |version: &str| ContractConfig {
+            chain_id: 4,
+            address: String::from("0x0000000000000000000000000000000000000001"),
+            name: String::from("FERRUM_TOKEN_BRIDGE_POOL"),
+            version: String::from(version),
+        };
+        let mut config = ProcessorConfig::default();
+        config.contracts.insert(String::from("RINKEBY"), vec![contract("000.002")]);
+        let p = SwapProcessor::new(&config, &RetryConfig::default(), MockValidator {}, MockDb::default(),
+            Arc::default(), shutdown_channel().1);
+        let res = p.process_withdraw_item(&withdraw_item("0x1")).await.ok().expect("Processing failed");
+        assert!(matches!(res, ItemOutcome::Invalid), "Must refuse an unknown contract version");
+        assert_eq!(p.db.added.load(Ordering::SeqCst), 0);
+
+        config.contracts.insert(String::from("RINKEBY"), vec![contract("000.003")]);
+        let p = SwapProcessor::new(&config, &RetryConfig::default(), MockValidator {}, MockDb::default(),
+            Arc::default(), shutdown_channel().1);
+        let res = p.process_withdraw_item(&withdraw_item("0x1")).await.ok().expect("Processing failed");
+        assert!(matches!(res, ItemOutcome::Signed(_)));
+    }
+
+    #[tokio::test]
+    async fn test_volume_cap_stops_signing() {
+        let mut config = ProcessorConfig::default();
+        config.caps.push(serde_json::from_str(r#"{"network": "RINKEBY",
+            "token": "0x0000000000000000000000000000000000000002", "windowSecs": 3600,
+            "maxAmount": "2500000000000000000"}"#).unwrap());
+        // Signed by another node, in the window and before it
+        let db = MockDb::default();
+        db.signed.lock().unwrap().push((now(), String::from("0xa"), String::from("1000000000000000000")));
+        db.signed.lock().unwrap().push(
+            (now() - 7_200_000, String::from("0xb"), String::from("5000000000000000000")));
+        let p = SwapProcessor::new(&config, &RetryConfig::default(), MockValidator {}, db, Arc::default(), shutdown_channel().1);
+        let res = p.process_withdraw_item(&withdraw_item("0x1")).await.ok().expect("Processing failed");
+        assert!(matches!(res, ItemOutcome::Signed(_)));
+        for tx in ["0x2", "0x3"] {
+            let res = p.process_withdraw_item(&withdraw_item(tx)).await.ok().expect("Processing failed");
+            assert!(matches!(res, ItemOutcome::CapReached), "Must stop signing once the cap is reached");
+        }
+        assert_eq!(p.db.added.load(Ordering::SeqCst), 1);
+
+        // Stays reached after a restart, even with nothing signed in the window
+        let reached = p.db.reached_caps.lock().unwrap().clone();
+        let db = MockDb { reached_caps: Mutex::new(reached), ..Default::default() };
+        let p = SwapProcessor::new(&config, &RetryConfig::default(), MockValidator {}, db, Arc::default(), shutdown_channel().1);
+        let res = p.process_withdraw_item(&withdraw_item("0x4")).await.ok().expect("Processing failed");
+        assert!(matches!(res, ItemOutcome::CapReached));
+
+        // Dry run does not record the cap as reached
+        let config = ProcessorConfig { dry_run: true, ..config };
+        let p = SwapProcessor::new(&config, &RetryConfig::default(), MockValidator {}, MockDb::default(), Arc::default(),
+            shutdown_channel().1);
+        let mut wi = withdraw_item("0x5");
+        wi.pay_by_sig.amount = String::from("3000000000000000000");
+        wi.pay_by_sig.hash = withdraw_signed_hash(&wi.pay_by_sig).ok().unwrap();
+        let res = p.process_withdraw_item(&wi).await.ok().expect("Processing failed");
+        assert!(matches!(res, ItemOutcome::CapReached));
+        assert!(p.db.reached_caps.lock().unwrap().is_empty());
+
+        // Amounts are only parsed when a cap applies
+        let p = SwapProcessor::new(&ProcessorConfig::default(), &RetryConfig::default(), MockValidator {}, MockDb::default(),
+            Arc::default(), shutdown_channel().1);
+        wi.pay_by_sig.amount = format!("{}0", u128::MAX);
+        wi.pay_by_sig.hash = withdraw_signed_hash(&wi.pay_by_sig).ok().unwrap();
+        let res = p.process_withdraw_item(&wi).await.ok().expect("Processing failed");
+        assert!(matches!(res, ItemOutcome::Signed(_)));
+
+        assert!(serde_json::from_str::<VolumeCapConfig>(r#"{"network": "RINKEBY",
+            "token": "0x0000000000000000000000000000000000000002", "windowSecs": 3600,
+            "maxAmount": "2.5"}"#).is_err(), "Bad maxAmount must be rejected");
+    }
+
+    #[tokio::test]
+    async fn test_super_signatures() {
+        let signature = |creator: &str| WithdrawItemSignature {
+            creation_time: 0,
+            creator: String::from(creator),
+            signature: String::from("0x05"),
+        };
+        let other = "0x0000000000000000000000000000000000000006";
+        let config = ProcessorConfig {
+            super_threshold: 2,
+            super_signers: vec![
+                Address::parse(MOCK_SIGNER).ok().unwrap(), Address::parse(other).ok().unwrap()],
+            ..Default::default()
+        };
+        let p = SwapProcessor::new(&config, &RetryConfig::default(), MockValidator {}, MockDb::default(),
+            Arc::default(), shutdown_channel().1);
+        // Another super signer signed. Ours is still needed
+        let mut wi = withdraw_item("0x1");
+        wi.pay_by_sig.signatures.push(signature(other));
+        let res = p.process_withdraw_item(&wi).await.ok().expect("Processing failed");
+        assert!(matches!(res, ItemOutcome::Signed(_)));
+        // We already signed
+        let mut wi = withdraw_item("0x2");
+        wi.pay_by_sig.signatures.push(signature(MOCK_SIGNER));
+        let res = p.process_withdraw_item(&wi).await.ok().expect("Processing failed");
+        assert!(matches!(res, ItemOutcome::AlreadySigned));
+        assert_eq!(p.db.added.load(Ordering::SeqCst), 1);
+
+        assert!(serde_json::from_str::<ProcessorConfig>(r#"{"superThreshold": 0}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_blocked_items_are_not_signed() {
+        let blocklist = Arc::new(Blocklist::default());
+        let p = SwapProcessor::new(&ProcessorConfig::default(), &RetryConfig::default(), MockValidator {}, MockDb::default(),
+            blocklist.clone(), shutdown_channel().1);
+        let mut entries = BlockedEntries::default();
+        entries.add("transaction", "0xA1").ok().unwrap();
+        blocklist.set(entries);
+        let res = p.process_withdraw_item(&withdraw_item("0xa1")).await.ok().expect("Processing failed");
+        assert!(matches!(res, ItemOutcome::Blocked), "Must refuse a blocked transaction");
+        let res = p.process_withdraw_item(&withdraw_item("0x2")).await.ok().expect("Processing failed");
+        assert!(matches!(res, ItemOutcome::Signed(_)));
+
+        // Picked up without a restart
+        let mut entries = BlockedEntries::default();
+        entries.add("recipient", "0x0000000000000000000000000000000000000003").ok().unwrap();
+        blocklist.set(entries);
+        let res = p.process_withdraw_item(&withdraw_item("0x3")).await.ok().expect("Processing failed");
+        assert!(matches!(res, ItemOutcome::Blocked), "Must refuse a blocked recipient");
+        assert_eq!(p.db.added.load(Ordering::SeqCst), 1);
+        let reports = p.db.reports.lock().unwrap();
+        assert_eq!(reports[0].outcome, "blocked");
+        assert!(reports[2].reason.contains("Recipient"));
+    }
+
+    #[tokio::test]
+    async fn test_transaction_with_another_super_signature_is_signed() {
+        let other = "0x0000000000000000000000000000000000000006";
+        let config = ProcessorConfig {
+            super_threshold: 2,
+            super_signers: vec![
+                Address::parse(MOCK_SIGNER).ok().unwrap(), Address::parse(other).ok().unwrap()],
+            ..Default::default()
+        };
+        let db = MockDb { super_signed_by: vec![String::from(other)], ..Default::default() };
+        let p = SwapProcessor::new(&config, &RetryConfig::default(), MockValidator {}, db, Arc::default(), shutdown_channel().1);
+        p.process_transaction("RINKEBY", "0x1").await.ok().expect("Processing failed");
+        assert_eq!(p.db.added.load(Ordering::SeqCst), 1, "Our signature is still needed");
+
+        let db = MockDb { super_signed_by: vec![String::from(MOCK_SIGNER)], ..Default::default() };
+        let p = SwapProcessor::new(&config, &RetryConfig::default(), MockValidator {}, db, Arc::default(), shutdown_channel().1);
+        p.process_transaction("RINKEBY", "0x1").await.ok().expect("Processing failed");
+        assert_eq!(p.db.added.load(Ordering::SeqCst), 0, "We already signed");
+    }
 }
@@ -1,17 +1,94 @@
+use super::blocklist::Blocklist;
 use crate::signer::key_provider::KeyProvider;
+use async_trait::async_trait;
+use crate::signer::ledger::SignLedger;
 use crate::signer::service::{SignerService, SignerServiceImpl};
-use crate::types::types::{SignedSwap, SignerConfig};
+use crate::types::address::Address;
+use crate::types::types::{SignedSwap, SignerConfig, SignerOverride, WithdrawItem};
 use crate::types::utils::now;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
-pub trait Validator {
-    fn is_multi_sig_valid(&self, msg: &String, all_signatures: &Vec<SignedSwap>) -> bool;
-    fn verify_sig(&self, msg: &String, s: &SignedSwap) -> bool;
-    fn produce_our_signature(
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MultiSigVerdict {
+    Valid,
+    /// Not enough valid signatures (yet)
+    BelowThreshold,
+    /// Signatures are inconsistent or could not be verified
+    Invalid,
+}
+
+/**
+Result of checking the validator signatures of a withdraw item, with the reasons
+when they are not enough.
+ **/
+#[derive(Clone, Debug)]
+pub struct MultiSigCheck {
+    pub verdict: MultiSigVerdict,
+    /// Empty if the signatures are valid
+    pub reason: String,
+    pub signatures: usize,
+    pub valid_weight: u64,
+    pub required_weight: u64,
+    /// Rejected signatures, as (signer, reason)
+    pub rejected: Vec<(String, String)>,
+}
+
+/**
+Signatures are checked against the withdraw item being signed. Its receive network
+selects the signer config.
+ **/
+#[async_trait]
+pub trait Validator: Send + Sync {
+    fn validate_multi_sig(&self, wi: &WithdrawItem, all_signatures: &[SignedSwap])
+        -> MultiSigCheck;
+    fn check_multi_sig(&self, wi: &WithdrawItem, all_signatures: &[SignedSwap])
+        -> MultiSigVerdict {
+        self.validate_multi_sig(wi, all_signatures).verdict
+    }
+    fn is_multi_sig_valid(&self, wi: &WithdrawItem, all_signatures: &[SignedSwap]) -> bool {
+        self.check_multi_sig(wi, all_signatures) == MultiSigVerdict::Valid
+    }
+    /// Why the signature does not count, if it does not
+    fn check_sig(&self, network: &str, msg: &str, s: &SignedSwap) -> Result<(), String>;
+    fn verify_sig(&self, network: &str, msg: &str, s: &SignedSwap) -> bool {
+        match self.check_sig(network, msg, s) {
+            Ok(()) => true,
+            Err(reason) => {
+                println!("Error verify signature. Rejecting {} - {}", s, reason);
+                false
+            }
+        }
+    }
+    /// In dry run, the ledger is checked but nothing is recorded
+    async fn produce_our_signature(
         &self,
-        msg: &String,
-        signatures: &Vec<SignedSwap>,
+        wi: &WithdrawItem,
+        signatures: &[SignedSwap],
+        dry_run: bool,
     ) -> Result<SignedSwap, ValidatorError>;
+    /// Whether the withdraw item already has our signature
+    fn has_signed(&self, wi: &WithdrawItem) -> bool;
+    fn wipe_key(&self);
+}
+
+
+/// Why the signature's creation time is not acceptable, if it is not
+fn stale_reason(config: &SignerConfig, creation_time: i64, now: i64) -> Option<String> {
+    if let Some(max_age) = config.max_signature_age_secs {
+        let age = now - creation_time;
+        if age > max_age as i64 * 1000 {
+            return Some(format!("signature is {}s old (max {}s)", age / 1000, max_age));
+        }
+    }
+    if let Some(max_skew) = config.max_clock_skew_secs {
+        let ahead = creation_time - now;
+        if ahead > max_skew as i64 * 1000 {
+            return Some(format!("signature is dated {}s in the future (max {}s)",
+                                ahead / 1000, max_skew));
+        }
+    }
+    None
 }
 
 pub struct ValidatorError {
@@ -20,119 +97,640 @@ pub struct ValidatorError {
 
 pub struct MultiSigValidator<KP: KeyProvider+Sized>  {
     config: SignerConfig,
+    network_configs: HashMap<String, SignerConfig>,
     signing_svc: SignerServiceImpl,
     key_provider: Box<KP>,
+    ledger: SignLedger,
+    blocklist: Arc<Blocklist>,
 }
 
 impl<KP: KeyProvider> MultiSigValidator<KP> {
-    pub fn new(config: &SignerConfig, signing_svc: SignerServiceImpl, kp: KP) -> Self {
+    pub fn new(
+        config: &SignerConfig,
+        overrides: &HashMap<String, SignerOverride>,
+        signing_svc: SignerServiceImpl,
+        kp: KP,
+        ledger: SignLedger,
+        blocklist: Arc<Blocklist>,
+    ) -> Self {
+        let network_configs = overrides
+            .iter()
+            .map(|(net, o)| (net.clone(), config.with_override(o)))
+            .collect();
         MultiSigValidator {
             config: config.clone(),
+            network_configs,
             signing_svc,
             key_provider: Box::new(kp),
+            ledger,
+            blocklist,
         }
     }
+
+    /// The signer config for the network, or the default one if it is not overridden
+    fn config_for(&self, network: &str) -> &SignerConfig {
+        self.network_configs.get(network).unwrap_or(&self.config)
+    }
 }
 
+
+#[async_trait]
 impl<KP: KeyProvider> Validator for MultiSigValidator<KP> {
     /**
-    Go through all the sig, make sure they are unique, and share the msg.
+    Go through all the sig, make sure they are unique, and are for the withdraw item.
+    The weights of the unique verified signers must add up to the required weight.
     **/
-    fn is_multi_sig_valid(&self, msg: &String, all_signatures: &Vec<SignedSwap>) -> bool {
-        let signatures: Vec<&SignedSwap> = all_signatures
-            .into_iter()
-            .filter(|s| msg.eq(&s.msg_hash))
-            .collect();
-        if signatures.len() == 0 {
-            return false;
-        }
-        if signatures.len() < self.config.min_threshold as usize {
-            let sig = &signatures[0];
-            println!(
-                "ignoring {}:{} - not enough signatures ({} of {})",
-                sig.network,
-                sig.transaction_id,
-                signatures.len(),
-                self.config.min_threshold
-            );
-            return false;
-        }
-        let expected_net = &signatures[0].network;
-        let expected_tix = &signatures[0].transaction_id;
-        for s in &signatures {
-            if expected_net.ne(&s.network) || expected_tix.ne(&s.transaction_id) {
-                println!(
-                    "Error - unexpected network ({}) or transactionId ({}) in {}",
-                    &expected_net, &expected_tix, s
-                );
-                return false;
+    fn validate_multi_sig(&self, wi: &WithdrawItem, all_signatures: &[SignedSwap])
+        -> MultiSigCheck {
+        let network = wi.receive_network.as_str();
+        let config = self.config_for(network);
+        let msg = wi.pay_by_sig.hash.clone();
+        let mut check = MultiSigCheck {
+            verdict: MultiSigVerdict::BelowThreshold,
+            reason: String::new(),
+            signatures: all_signatures.len(),
+            valid_weight: 0,
+            required_weight: config.required_weight(),
+            rejected: vec![],
+        };
+        let done = |mut check: MultiSigCheck, verdict: MultiSigVerdict, reason: String| {
+            println!("{}:{} - {}", network, &wi.receive_transaction_id, &reason);
+            check.verdict = verdict;
+            check.reason = reason;
+            check
+        };
+        if all_signatures.is_empty() {
+            return done(check, MultiSigVerdict::BelowThreshold, String::from("No validator signatures"));
+        }
+        // Short of the required weight: invalid if some signatures were rejected, as
+        // opposed to simply missing
+        let short_verdict = |check: &MultiSigCheck| match check.rejected.is_empty() {
+            true => MultiSigVerdict::BelowThreshold,
+            false => MultiSigVerdict::Invalid,
+        };
+        // A signature made for another item must not count towards this one, and
+        // signers are compared as addresses, whatever their casing
+        let mut signatures: Vec<(Address, &SignedSwap)> = vec![];
+        for s in all_signatures {
+            if network.ne(&s.network)
+                || !wi.receive_transaction_id.eq_ignore_ascii_case(&s.transaction_id)
+                || !msg.eq_ignore_ascii_case(&s.msg_hash) {
+                println!("Ignoring a signature that does not match the withdraw item ({}): {}",
+                         &msg, s);
+                check.rejected.push((s.signer.clone(), String::from("Not for this withdraw item")));
+                continue;
             }
+            match Address::parse(&s.signer) {
+                Ok(a) => signatures.push((a, s)),
+                Err(e) => {
+                    println!("Ignoring a signature - {}", &e.msg);
+                    check.rejected.push((s.signer.clone(), e.msg));
+                },
+            }
+        }
+        let required = check.required_weight;
+        let claimed: u64 = signatures
+            .iter()
+            .map(|(a, _)| *a)
+            .collect::<HashSet<Address>>()
+            .iter()
+            .map(|signer| config.weight_of(signer))
+            .sum();
+        if claimed < required {
+            let reason = format!(
+                "Not enough signatures ({} signatures, weight {} of {})",
+                signatures.len(), claimed, required);
+            let verdict = short_verdict(&check);
+            return done(check, verdict, reason);
         }
-        let mut deduped: HashMap<String, &SignedSwap> = HashMap::new();
-        signatures.into_iter().for_each(|s| {
-            deduped.insert(s.signer.clone(), s);
+        let mut deduped: HashMap<Address, &SignedSwap> = HashMap::new();
+        signatures.into_iter().for_each(|(a, s)| {
+            deduped.insert(a, s);
         });
-        if deduped.len() == 0 {
-            return false;
-        }
-        let valid = deduped
-            .values()
-            .into_iter()
-            .filter(|s| self.verify_sig(&msg, s))
-            .count();
-        let meets_thr = valid >= self.config.min_threshold as usize;
-        if !meets_thr {
-            println!(
-                "Could not validate msg '{}' because not enough signatures were available",
-                msg
-            );
-            return false;
-        }
-        meets_thr
-    }
-
-    fn verify_sig(&self, msg: &String, s: &SignedSwap) -> bool {
-        let recovered: String = self.signing_svc.recover(msg, &s.signature);
-        if recovered.ne(&s.signer) {
-            println!(
-                "Error verify signature. Provided signature doesn't match the record {} - {}",
-                &recovered,
-                s
-            );
-            return false;
+        let mut valid: Vec<(&Address, &&SignedSwap)> = vec![];
+        for (a, s) in deduped.iter() {
+            match self.check_sig(network, &s.msg_hash, s) {
+                Ok(()) => valid.push((a, s)),
+                Err(reason) => {
+                    println!("Error verify signature. Rejecting {} - {}", s, &reason);
+                    check.rejected.push((s.signer.clone(), reason));
+                },
+            }
+        }
+        check.valid_weight = valid
+            .iter()
+            .filter_map(|(a, s)| config.active_validator(a, s.creation_time))
+            .map(|v| v.weight)
+            .sum();
+        if check.valid_weight < required {
+            let reason = format!(
+                "Not enough valid signatures for msg '{}' (weight {} of {}, {} rejected)",
+                msg, check.valid_weight, required, check.rejected.len());
+            let verdict = short_verdict(&check);
+            return done(check, verdict, reason);
+        }
+        check.verdict = MultiSigVerdict::Valid;
+        check
+    }
+
+    fn check_sig(&self, network: &str, msg: &str, s: &SignedSwap) -> Result<(), String> {
+        let recovered = self.signing_svc
+            .recover_strict(msg, &s.signature)
+            .and_then(|r| Address::parse(&r))
+            .map_err(|e| e.msg)?;
+        let signer = Address::parse(&s.signer).map_err(|e| e.msg)?;
+        if recovered.ne(&signer) {
+            return Err(format!(
+                "Provided signature doesn't match the record. Recovered {}", &recovered));
         }
         // Make sure the recovered signature is configured here
-        let valid = &self.config.validators;
-        let from_list = valid.into_iter().any(|v| v.eq(&s.signer));
+        let config = self.config_for(network);
+        let from_list = config.validators.iter().any(|v| v.address.eq(&signer));
         if !from_list {
-            println!(
-                "Error! received a signature from '{}', but signer is not configured",
-                &s.signer
-            );
-            return false;
+            return Err(String::from("Signer is not configured"));
+        }
+        if self.blocklist.is_validator_blocked(&signer) {
+            return Err(String::from("Signer is blocked"));
+        }
+        if let Some(reason) = stale_reason(config, s.creation_time, now()) {
+            return Err(reason);
         }
-        true
+        // And that the key was active when it signed
+        if config.active_validator(&signer, s.creation_time).is_none() {
+            return Err(format!(
+                "Signature was created at {}, outside of the signer's validity window",
+                s.creation_time));
+        }
+        Ok(())
     }
 
-    fn produce_our_signature(
+
+    async fn produce_our_signature(
         &self,
-        msg: &String,
-        signatures: &Vec<SignedSwap>,
+        wi: &WithdrawItem,
+        signatures: &[SignedSwap],
+        dry_run: bool,
     ) -> Result<SignedSwap, ValidatorError> {
-        if !self.is_multi_sig_valid(&msg, &signatures) {
+        if !self.is_multi_sig_valid(wi, signatures) {
             return Err(ValidatorError {
                 msg: String::from("Multisig is not valid"),
             });
         }
         let kp: &KP = self.key_provider.as_ref();
-        let my_sig = &self.signing_svc.sign(&msg, &kp.get_sk());
+        let sk = kp.get_sk();
+        if sk.is_empty() {
+            return Err(ValidatorError {
+                msg: String::from("Key is not available"),
+            });
+        }
+        let msg = &wi.pay_by_sig.hash;
+        // Never sign two different hashes for the same transaction
+        let recorded = match dry_run {
+            true => self.ledger.check(&wi.receive_network, &wi.receive_transaction_id, msg),
+            false => self.ledger.record(&wi.receive_network, &wi.receive_transaction_id, msg).await,
+        };
+        recorded.map_err(|e| ValidatorError { msg: e.msg })?;
+        let my_sig = &self.signing_svc.sign(msg, &sk);
         Ok(SignedSwap {
-            signer: self.config.address.clone(),
-            network: signatures[0].network.clone(),
-            transaction_id: signatures[0].transaction_id.clone(),
+            signer: self.config_for(&wi.receive_network).address.to_string(),
+            network: wi.receive_network.clone(),
+            transaction_id: wi.receive_transaction_id.clone(),
             msg_hash: msg.clone(),
             creation_time: now(),
             signature: my_sig.clone(),
         })
     }
+
+    fn has_signed(&self, wi: &WithdrawItem) -> bool {
+        let ours = self.config_for(&wi.receive_network).address;
+        wi.pay_by_sig
+            .signatures
+            .iter()
+            .any(|s| Address::parse(&s.creator).ok() == Some(ours))
+    }
+
+    fn wipe_key(&self) {
+        self.key_provider.wipe();
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::{MultiSigValidator, MultiSigVerdict, Validator};
+    #[allow(unused_imports)]
+    use crate::types::utils::now;
+    #[allow(unused_imports)]
+    use crate::types::address::Address;
+    #[allow(unused_imports)]
+    use crate::crypto::crypto_utils::{b2h, h2b, private_to_address, CryptoUtils};
+    #[allow(unused_imports)]
+    use crate::signer::key_provider::KeyProvider;
+    #[allow(unused_imports)]
+    use crate::signer::ledger::SignLedger;
+    #[allow(unused_imports)]
+    use crate::crypto::crypto_utils::rand_hex;
+    #[allow(unused_imports)]
+    use crate::signer::service::{SignerService, SignerServiceImpl};
+    #[allow(unused_imports)]
+    use crate::types::types::{AppConfig, PayBySig, SignedSwap, SignerConfig, SignerOverride, WithdrawItem};
+    #[allow(unused_imports)]
+    use crate::validator::blocklist::{BlockedEntries, Blocklist};
+    #[allow(unused_imports)]
+    use std::collections::HashMap;
+    #[allow(unused_imports)]
+    use std::sync::Arc;
+
+    #[allow(dead_code)]
+    const NET: &str = "RINKEBY";
+    #[allow(dead_code)]
+    const MSG: &str = "0x1a15b1ea0d007ed0e4262248d3406e310474b14bb6434266a5f941eaf86081ce";
+    #[allow(dead_code)]
+    const SUPER_SK: &str = "915c8bf73c84c0482beef48bb4bf782892d38d57d3c9af32de6af27a54d12c5a";
+    #[allow(dead_code)]
+    const VALIDATOR_SKS: [&str; 3] = [
+        "1111111111111111111111111111111111111111111111111111111111111111",
+        "2222222222222222222222222222222222222222222222222222222222222222",
+        "3333333333333333333333333333333333333333333333333333333333333333",
+    ];
+
+    #[allow(dead_code)]
+    struct TestKeyProvider {}
+
+    impl KeyProvider for TestKeyProvider {
+        fn get_sk(&self) -> String {
+            String::from(SUPER_SK)
+        }
+
+        fn wipe(&self) {}
+    }
+
+    #[allow(dead_code)]
+    fn address_of(sk: &str) -> String {
+        format!("0x{}", b2h(&private_to_address(&h2b(&String::from(sk)))))
+    }
+
+    #[allow(dead_code)]
+    fn signed_swap(sk: &str) -> SignedSwap {
+        let signer = SignerServiceImpl::new(Box::new(CryptoUtils::new()));
+        SignedSwap {
+            creation_time: 0,
+            network: String::from(NET),
+            transaction_id: String::from("0x1"),
+            msg_hash: String::from(MSG),
+            signer: address_of(sk),
+            signature: signer.sign(&String::from(MSG), &String::from(sk)),
+        }
+    }
+
+    #[allow(dead_code)]
+    fn withdraw_item(network: &str) -> WithdrawItem {
+        WithdrawItem {
+            v: 0,
+            version: String::from("1.0"),
+            receive_network: String::from(network),
+            receive_transaction_id: String::from("0x1"),
+            send_network: String::from("BSC_TESTNET"),
+            pay_by_sig: PayBySig {
+                token: String::new(),
+                payee: String::new(),
+                amount: String::new(),
+                source_chain_id: 4,
+                swap_tx_id: String::from("0x1"),
+                contract_name: String::new(),
+                contract_version: String::new(),
+                contract_address: String::new(),
+                hash: String::from(MSG),
+                signatures: vec![],
+            },
+            signatures: 0,
+        }
+    }
+
+    /// An empty ledger in a new temp file
+    #[allow(dead_code)]
+    fn ledger() -> SignLedger {
+        SignLedger::open(&std::env::temp_dir().join(format!("sign-ledger-{}", rand_hex(8))))
+            .ok()
+            .unwrap()
+    }
+
+    #[allow(dead_code)]
+    fn validator(config_json: &str) -> MultiSigValidator<TestKeyProvider> {
+        let config: SignerConfig = serde_json::from_str(config_json).unwrap();
+        MultiSigValidator::new(
+            &config,
+            &HashMap::new(),
+            SignerServiceImpl::new(Box::new(CryptoUtils::new())),
+            TestKeyProvider {},
+            ledger(),
+            Arc::default())
+    }
+
+    #[test]
+    fn test_weighted_threshold() {
+        let config = format!(
+            r#"{{"address": "{}", "minThreshold": 2, "minWeight": 3, "validators": [
+                {{"address": "{}", "weight": 3}}, "{}", {{"address": "{}"}}]}}"#,
+            address_of(SUPER_SK),
+            address_of(VALIDATOR_SKS[0]),
+            address_of(VALIDATOR_SKS[1]),
+            address_of(VALIDATOR_SKS[2]));
+        let v = validator(&config);
+        let heavy = signed_swap(VALIDATOR_SKS[0]);
+        let light1 = signed_swap(VALIDATOR_SKS[1]);
+        let light2 = signed_swap(VALIDATOR_SKS[2]);
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), std::slice::from_ref(&heavy)), MultiSigVerdict::Valid);
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), &[light1.clone(), light2.clone()]),
+                   MultiSigVerdict::BelowThreshold);
+        // The same signer twice only counts once
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), &[light1.clone(), light1.clone(), light2.clone()]),
+                   MultiSigVerdict::BelowThreshold);
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), &[heavy, light1]), MultiSigVerdict::Valid);
+    }
+
+    #[test]
+    fn test_unweighted_threshold() {
+        let config = format!(
+            r#"{{"address": "{}", "minThreshold": 2, "validators": ["{}", "{}"]}}"#,
+            address_of(SUPER_SK),
+            address_of(VALIDATOR_SKS[0]),
+            address_of(VALIDATOR_SKS[1]));
+        let v = validator(&config);
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), &[signed_swap(VALIDATOR_SKS[0])]),
+                   MultiSigVerdict::BelowThreshold);
+        assert_eq!(v.check_multi_sig(
+            &withdraw_item(NET), &[signed_swap(VALIDATOR_SKS[0]), signed_swap(VALIDATOR_SKS[1])]),
+                   MultiSigVerdict::Valid);
+        // Unknown signers do not count
+        assert_eq!(v.check_multi_sig(
+            &withdraw_item(NET), &[signed_swap(VALIDATOR_SKS[0]), signed_swap(VALIDATOR_SKS[2])]),
+                   MultiSigVerdict::BelowThreshold);
+    }
+
+    #[tokio::test]
+    async fn test_network_overrides() {
+        let config = format!(
+            r#"{{"signer": {{"address": "{super_addr}", "minThreshold": 1, "validators": ["{v0}"]}},
+                "networkSigners": {{"BSC": {{"minThreshold": 2, "validators": ["{v1}", "{v2}"]}}}},
+                "db": {{"connectionString": "", "database": ""}},
+                "twoFa": {{"url": "", "hmacPublicKey": "", "hmacSecretKey": "", "twoFaId": ""}},
+                "encKey": ""}}"#,
+            super_addr = address_of(SUPER_SK),
+            v0 = address_of(VALIDATOR_SKS[0]),
+            v1 = address_of(VALIDATOR_SKS[1]),
+            v2 = address_of(VALIDATOR_SKS[2]));
+        let c = AppConfig::from_str(&config);
+        let v = MultiSigValidator::new(
+            &c.signer,
+            &c.network_signers,
+            SignerServiceImpl::new(Box::new(CryptoUtils::new())),
+            TestKeyProvider {},
+            ledger(),
+            Arc::default());
+        let on_bsc = |sk: &str| SignedSwap { network: String::from("BSC"), ..signed_swap(sk) };
+        // Networks without an override use the top level signer
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), &[signed_swap(VALIDATOR_SKS[0])]),
+                   MultiSigVerdict::Valid);
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), &[signed_swap(VALIDATOR_SKS[1])]),
+                   MultiSigVerdict::BelowThreshold);
+        assert_eq!(v.check_multi_sig(&withdraw_item("BSC"), &[on_bsc(VALIDATOR_SKS[0])]),
+                   MultiSigVerdict::BelowThreshold);
+        assert_eq!(v.check_multi_sig(&withdraw_item("BSC"), &[on_bsc(VALIDATOR_SKS[1])]),
+                   MultiSigVerdict::BelowThreshold);
+        assert_eq!(v.check_multi_sig(
+            &withdraw_item("BSC"), &[on_bsc(VALIDATOR_SKS[1]), on_bsc(VALIDATOR_SKS[2])]),
+                   MultiSigVerdict::Valid);
+        let ours = v.produce_our_signature(
+            &withdraw_item("BSC"), &[on_bsc(VALIDATOR_SKS[1]), on_bsc(VALIDATOR_SKS[2])], false).await;
+        assert_eq!(ours.ok().map(|s| s.signer), Some(address_of(SUPER_SK)));
+        let other_address = format!(r#"{{"address": "{}"}}"#, address_of(VALIDATOR_SKS[0]));
+        assert!(serde_json::from_str::<SignerOverride>(&other_address).is_err(),
+                "The node signs with a single key");
+    }
+
+    #[test]
+    fn test_zero_threshold_is_rejected() {
+        let config = |threshold: &str| format!(
+            r#"{{"address": "{}", {}, "validators": ["{}"]}}"#,
+            address_of(SUPER_SK), threshold, address_of(VALIDATOR_SKS[0]));
+        assert!(serde_json::from_str::<SignerConfig>(&config(r#""minThreshold": 1"#)).is_ok());
+        assert!(serde_json::from_str::<SignerConfig>(&config(r#""minThreshold": 0"#)).is_err());
+        assert!(serde_json::from_str::<SignerConfig>(
+            &config(r#""minThreshold": 1, "minWeight": 0"#)).is_err());
+        assert!(serde_json::from_str::<SignerOverride>(r#"{"minWeight": 2}"#).is_ok());
+        assert!(serde_json::from_str::<SignerOverride>(r#"{"minThreshold": 0}"#).is_err());
+        assert!(serde_json::from_str::<SignerOverride>(r#"{"minWeight": 0}"#).is_err());
+    }
+
+    #[test]
+    fn test_validity_window() {
+        let config = format!(
+            r#"{{"address": "{}", "minThreshold": 1, "validators": [
+                {{"address": "{}", "validUntil": 1000}}, {{"address": "{}", "validFrom": 1000}}]}}"#,
+            address_of(SUPER_SK),
+            address_of(VALIDATOR_SKS[0]),
+            address_of(VALIDATOR_SKS[1]));
+        let v = validator(&config);
+        let at = |sk: &str, creation_time: i64| SignedSwap { creation_time, ..signed_swap(sk) };
+        // The retired key is accepted for signatures it made while active
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), &[at(VALIDATOR_SKS[0], 999)]),
+                   MultiSigVerdict::Valid);
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), &[at(VALIDATOR_SKS[0], 1001)]),
+                   MultiSigVerdict::Invalid);
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), &[at(VALIDATOR_SKS[1], 999)]),
+                   MultiSigVerdict::Invalid);
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), &[at(VALIDATOR_SKS[1], 1000)]),
+                   MultiSigVerdict::Valid);
+    }
+
+    #[test]
+    fn test_signature_freshness() {
+        let config = format!(
+            r#"{{"address": "{}", "minThreshold": 1, "validators": ["{}"],
+                "maxSignatureAgeSecs": 3600, "maxClockSkewSecs": 60}}"#,
+            address_of(SUPER_SK),
+            address_of(VALIDATOR_SKS[0]));
+        let v = validator(&config);
+        let at = |creation_time: i64| SignedSwap { creation_time, ..signed_swap(VALIDATOR_SKS[0]) };
+        let t = now();
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), &[at(t - 60_000)]), MultiSigVerdict::Valid);
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), &[at(t + 30_000)]), MultiSigVerdict::Valid);
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), &[at(t - 7_200_000)]), MultiSigVerdict::Invalid);
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), &[at(t + 600_000)]), MultiSigVerdict::Invalid);
+    }
+
+    #[test]
+    fn test_signatures_must_match_withdraw_item() {
+        let config = format!(
+            r#"{{"address": "{}", "minThreshold": 1, "validators": ["{}", "{}"]}}"#,
+            address_of(SUPER_SK),
+            address_of(VALIDATOR_SKS[0]),
+            address_of(VALIDATOR_SKS[1]));
+        let v = validator(&config);
+        let wi = withdraw_item(NET);
+        let good = signed_swap(VALIDATOR_SKS[0]);
+        let other_tx = SignedSwap { transaction_id: String::from("0x2"), ..signed_swap(VALIDATOR_SKS[1]) };
+        let other_net = SignedSwap { network: String::from("BSC"), ..signed_swap(VALIDATOR_SKS[1]) };
+        let other_hash = SignedSwap { msg_hash: String::from("0x00"), ..signed_swap(VALIDATOR_SKS[1]) };
+        let bad_signer = SignedSwap { signer: String::from("junk"), ..signed_swap(VALIDATOR_SKS[1]) };
+        assert_eq!(v.check_multi_sig(&wi, std::slice::from_ref(&good)), MultiSigVerdict::Valid);
+        // Records for another item are skipped and reported, without blocking the valid ones
+        for junk in [other_tx, other_net, other_hash, bad_signer] {
+            let check = v.validate_multi_sig(&wi, &[junk.clone(), good.clone()]);
+            assert_eq!(check.verdict, MultiSigVerdict::Valid);
+            assert_eq!(check.rejected.len(), 1);
+            assert_eq!(v.check_multi_sig(&wi, &[junk]), MultiSigVerdict::Invalid);
+        }
+        // Valid signatures for another item cannot be reused
+        assert_eq!(v.check_multi_sig(&withdraw_item("BSC"), &[good]), MultiSigVerdict::Invalid);
+    }
+
+    /// The same signature with s replaced by n - s, and v flipped
+    #[allow(dead_code)]
+    fn high_s(sig: &str) -> String {
+        const N: [u8; 32] = [
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+            0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41,
+        ];
+        let mut b = h2b(&String::from(sig));
+        let mut borrow = 0i16;
+        for i in (0..32).rev() {
+            let d = N[i] as i16 - b[32 + i] as i16 - borrow;
+            borrow = if d < 0 { 1 } else { 0 };
+            b[32 + i] = (d + 256 * borrow) as u8;
+        }
+        b[64] = if b[64] == 27 { 28 } else { 27 };
+        b2h(&b)
+    }
+
+    #[test]
+    fn test_non_canonical_signatures_are_rejected() {
+        let config = format!(
+            r#"{{"address": "{}", "minThreshold": 1, "validators": ["{}"]}}"#,
+            address_of(SUPER_SK),
+            address_of(VALIDATOR_SKS[0]));
+        let v = validator(&config);
+        let msg = String::from(MSG);
+        let good = signed_swap(VALIDATOR_SKS[0]);
+        assert!(v.verify_sig(NET, &msg, &good));
+        let with_sig = |signature: String| SignedSwap { signature, ..good.clone() };
+        // v as 0/1 is accepted
+        let mut sig = h2b(&good.signature);
+        sig[64] -= 27;
+        assert!(v.verify_sig(NET, &msg, &with_sig(b2h(&sig))));
+        // Other v values and high s are not
+        sig[64] = 29;
+        assert!(!v.verify_sig(NET, &msg, &with_sig(b2h(&sig))));
+        // The high s variant is a second encoding of the same signature
+        assert_eq!(high_s(&high_s(&good.signature)), good.signature);
+        assert!(!v.verify_sig(NET, &msg, &with_sig(high_s(&good.signature))));
+        assert!(!v.verify_sig(NET, &msg, &with_sig(String::from("0xzz"))));
+    }
+
+    #[test]
+    fn test_addresses_match_in_any_casing() {
+        let checksummed = |sk: &str| Address::parse(&address_of(sk)).ok().unwrap().to_checksum();
+        let config = format!(
+            r#"{{"address": "{}", "minThreshold": 2, "validators": ["{}", "{}"]}}"#,
+            checksummed(SUPER_SK),
+            checksummed(VALIDATOR_SKS[0]),
+            address_of(VALIDATOR_SKS[1]));
+        let v = validator(&config);
+        let wi = withdraw_item(NET);
+        let as_checksummed = |sk: &str| SignedSwap { signer: checksummed(sk), ..signed_swap(sk) };
+        assert_eq!(v.check_multi_sig(
+            &wi, &[signed_swap(VALIDATOR_SKS[0]), as_checksummed(VALIDATOR_SKS[1])]),
+                   MultiSigVerdict::Valid);
+        // The same signer in two casings only counts once
+        assert_eq!(v.check_multi_sig(
+            &wi, &[signed_swap(VALIDATOR_SKS[0]), as_checksummed(VALIDATOR_SKS[0])]),
+                   MultiSigVerdict::BelowThreshold);
+        let bad_checksum = SignedSwap {
+            signer: checksummed(VALIDATOR_SKS[1]).to_lowercase().replacen('a', "A", 1),
+            ..signed_swap(VALIDATOR_SKS[1])
+        };
+        assert!(!v.verify_sig(NET, &String::from(MSG), &bad_checksum));
+        let bad_config = format!(
+            r#"{{"address": "{}", "minThreshold": 1, "validators": ["{}"]}}"#,
+            address_of(SUPER_SK), &bad_checksum.signer);
+        assert!(serde_json::from_str::<SignerConfig>(&bad_config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refuses_to_sign_a_second_hash() {
+        let config = format!(
+            r#"{{"address": "{}", "minThreshold": 1, "validators": ["{}"]}}"#,
+            address_of(SUPER_SK),
+            address_of(VALIDATOR_SKS[0]));
+        let config: SignerConfig = serde_json::from_str(&config).unwrap();
+        let ledger = ledger();
+        ledger.record(NET, "0x1", "0x00").await.ok().unwrap();
+        let v = MultiSigValidator::new(
+            &config,
+            &HashMap::new(),
+            SignerServiceImpl::new(Box::new(CryptoUtils::new())),
+            TestKeyProvider {},
+            ledger,
+            Arc::default());
+        let wi = withdraw_item(NET);
+        let sigs = [signed_swap(VALIDATOR_SKS[0])];
+        assert_eq!(v.check_multi_sig(&wi, &sigs), MultiSigVerdict::Valid);
+        assert!(v.produce_our_signature(&wi, &sigs, false).await.is_err());
+        assert!(v.produce_our_signature(&wi, &sigs, true).await.is_err(), "Dry run checks the ledger");
+        let on_bsc = [SignedSwap { network: String::from("BSC"), ..signed_swap(VALIDATOR_SKS[0]) }];
+        // Dry run records nothing
+        assert!(v.produce_our_signature(&withdraw_item("BSC"), &on_bsc, true).await.is_ok());
+        assert!(v.ledger.check("BSC", "0x1", "0x00").is_ok());
+        assert!(v.produce_our_signature(&withdraw_item("BSC"), &on_bsc, false).await.is_ok());
+        assert!(v.ledger.check("BSC", "0x1", "0x00").is_err());
+    }
+
+    #[test]
+    fn test_check_reports_rejected_signatures() {
+        let config = format!(
+            r#"{{"address": "{}", "minThreshold": 1, "validators": ["{}"]}}"#,
+            address_of(SUPER_SK),
+            address_of(VALIDATOR_SKS[0]));
+        let v = validator(&config);
+        let forged = SignedSwap { signer: address_of(VALIDATOR_SKS[0]), ..signed_swap(VALIDATOR_SKS[1]) };
+        let check = v.validate_multi_sig(&withdraw_item(NET), &[forged]);
+        assert_eq!(check.verdict, MultiSigVerdict::Invalid);
+        assert_eq!((check.signatures, check.valid_weight, check.required_weight), (1, 0, 1));
+        assert_eq!(check.rejected.len(), 1);
+        assert_eq!(check.rejected[0].0, address_of(VALIDATOR_SKS[0]));
+        assert!(check.rejected[0].1.contains("doesn't match"));
+        assert!(!check.reason.is_empty());
+        let check = v.validate_multi_sig(&withdraw_item(NET), &[signed_swap(VALIDATOR_SKS[0])]);
+        assert_eq!(check.verdict, MultiSigVerdict::Valid);
+        assert!(check.reason.is_empty() && check.rejected.is_empty());
+    }
+
+    #[test]
+    fn test_blocked_validator_is_rejected() {
+        let config = format!(
+            r#"{{"address": "{}", "minThreshold": 1, "validators": ["{}", "{}"]}}"#,
+            address_of(SUPER_SK),
+            address_of(VALIDATOR_SKS[0]),
+            address_of(VALIDATOR_SKS[1]));
+        let config: SignerConfig = serde_json::from_str(&config).unwrap();
+        let blocklist = Arc::new(Blocklist::default());
+        let v = MultiSigValidator::new(
+            &config,
+            &HashMap::new(),
+            SignerServiceImpl::new(Box::new(CryptoUtils::new())),
+            TestKeyProvider {},
+            ledger(),
+            blocklist.clone());
+        let sigs = [signed_swap(VALIDATOR_SKS[0])];
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), &sigs), MultiSigVerdict::Valid);
+        let mut entries = BlockedEntries::default();
+        entries.add("validator", &address_of(VALIDATOR_SKS[0])).ok().unwrap();
+        blocklist.set(entries);
+        let check = v.validate_multi_sig(&withdraw_item(NET), &sigs);
+        assert_eq!(check.verdict, MultiSigVerdict::Invalid);
+        assert_eq!(check.rejected[0].1, "Signer is blocked");
+        // Other validators still count
+        assert_eq!(v.check_multi_sig(&withdraw_item(NET), &[signed_swap(VALIDATOR_SKS[1])]),
+                   MultiSigVerdict::Valid);
+    }
 }
@@ -0,0 +1,144 @@
+use crate::database::mongo::Database;
+use crate::types::address::Address;
+use crate::types::errors::{BError, BResult};
+use crate::types::types::VolumeCapConfig;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Amounts counted against the caps for one item, until it is written or dropped
+pub struct Reservation {
+    caps: Vec<usize>,
+    transaction_id: String,
+}
+
+pub enum CapCheck {
+    Reserved(Reservation),
+    /// A cap is reached. Nothing was counted
+    Reached(String),
+}
+
+/**
+Rolling window caps on the signed amounts, per network and token. The volume is
+read from the super signatures in the db, so it is shared by all the nodes and
+survives restarts. A reached cap is recorded in the db too, and stays closed
+until an operator removes its record.
+ **/
+pub struct VolumeCaps {
+    caps: Vec<VolumeCapConfig>,
+    /// Amounts of the items being signed by this node, by transaction id, per cap.
+    /// They are not in the db yet
+    in_flight: Mutex<Vec<HashMap<String, u128>>>,
+    /// Do not record the reached caps
+    dry_run: bool,
+}
+
+fn cap_key(cap: &VolumeCapConfig) -> String {
+    format!("{}:{}", &cap.network, &cap.token)
+}
+
+impl VolumeCaps {
+    pub fn new(config: &[VolumeCapConfig], dry_run: bool) -> Self {
+        VolumeCaps {
+            caps: config.to_vec(),
+            in_flight: Mutex::new(config.iter().map(|_| HashMap::new()).collect()),
+            dry_run,
+        }
+    }
+
+    /**
+    Counts the amount against every cap for the network and token. If that would
+    exceed a cap, nothing is counted, the cap is closed and an alert is raised. The
+    amount is only parsed if a cap applies.
+     **/
+    pub async fn reserve<D: Database>(
+        &self,
+        db: &D,
+        network: &str,
+        transaction_id: &str,
+        token: &str,
+        amount: &str,
+        now: i64,
+    ) -> BResult<CapCheck> {
+        let applicable: Vec<usize> = match self.caps.iter().any(|c| c.network.eq(network)) {
+            false => vec![],
+            true => {
+                let token = Address::parse(token)?;
+                self.caps
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, c)| c.network.eq(network) && c.token.eq(&token))
+                    .map(|(i, _)| i)
+                    .collect()
+            },
+        };
+        let tx = transaction_id.to_lowercase();
+        if applicable.is_empty() {
+            return Ok(CapCheck::Reserved(Reservation { caps: applicable, transaction_id: tx }));
+        }
+        let amount: u128 = amount.parse().map_err(|_| BError::new(&format!(
+            "Cannot parse the amount '{}'", amount)))?;
+        let db_err = |e: mongodb::error::Error| BError::new(&format!(
+            "Error reading the signed volume: {}", e));
+        // Totals of the items signed in the window, other than this one
+        let mut totals: Vec<u128> = vec![];
+        for &i in &applicable {
+            let cap = &self.caps[i];
+            if db.is_volume_cap_reached(&cap_key(cap)).await.map_err(db_err)? {
+                return Ok(CapCheck::Reached(format!(
+                    "Volume cap for {} was reached. Signing is halted until it is reset",
+                    cap_key(cap))));
+            }
+            let since = now - cap.window_secs as i64 * 1000;
+            let mut total = 0u128;
+            for (t, a) in db.signed_amounts(network, &cap.token, since).await.map_err(db_err)? {
+                if !t.eq_ignore_ascii_case(&tx) {
+                    let a: u128 = a.parse().map_err(|_| BError::new(&format!(
+                        "Cannot parse the signed amount '{}' of {}", &a, &t)))?;
+                    total = total.saturating_add(a);
+                }
+            }
+            totals.push(total);
+        }
+        let exceeded = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            let exceeded = applicable.iter().zip(totals.iter()).find_map(|(&i, &db_total)| {
+                let total = in_flight[i]
+                    .iter()
+                    .filter(|(t, _)| !t.eq(&&tx))
+                    .fold(db_total, |acc, (_, a)| acc.saturating_add(*a));
+                match total.saturating_add(amount) > self.caps[i].max_amount {
+                    true => Some((i, total)),
+                    false => None,
+                }
+            });
+            if exceeded.is_none() {
+                for &i in &applicable {
+                    in_flight[i].insert(tx.clone(), amount);
+                }
+            }
+            exceeded
+        };
+        if let Some((i, total)) = exceeded {
+            let cap = &self.caps[i];
+            let reason = format!(
+                "{} already signed in the last {}s, {} more requested, max is {}",
+                total, cap.window_secs, amount, cap.max_amount);
+            println!("ALERT: volume cap reached for {}. {}. Signing is halted for this token",
+                     cap_key(cap), &reason);
+            if !self.dry_run {
+                db.set_volume_cap_reached(&cap_key(cap), &reason).await.map_err(|e| BError::new(
+                    &format!("Error recording the volume cap {} as reached: {}", cap_key(cap), e)))?;
+            }
+            return Ok(CapCheck::Reached(format!("Volume cap for {} reached", cap_key(cap))));
+        }
+        Ok(CapCheck::Reserved(Reservation { caps: applicable, transaction_id: tx }))
+    }
+
+    /// Stops counting the item in memory. Once signed, it is counted from the db
+    pub fn release(&self, r: &Reservation) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        for &i in &r.caps {
+            in_flight[i].remove(&r.transaction_id);
+        }
+    }
+}